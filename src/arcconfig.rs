@@ -1,15 +1,24 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(thiserror::Error, Debug)]
 pub(crate) enum Error {
     #[error("could not obtain the current working directory")]
     CurrentDir(#[source] std::io::Error),
 
-    #[error("could not open .arcconfig: {1:?}")]
+    #[error("could not open {1:?}")]
     OpenArcConfig(#[source] std::io::Error, PathBuf),
 
-    #[error("could not find any directory with `.arcconfig` containing `repository.callsign` in it")]
-    FindArcConfig,
+    #[error("could not parse {1:?}")]
+    ParseArcRc(#[source] serde_json::Error, PathBuf),
+
+    #[error("could not parse {1:?} as JSON")]
+    ParseArcConfig(#[source] serde_json::Error, PathBuf),
+}
+
+impl crate::ClassifyError for Error {
+    fn kind(&self) -> crate::ErrorKind {
+        crate::ErrorKind::User
+    }
 }
 
 #[derive(serde::Deserialize)]
@@ -26,32 +35,200 @@ pub(crate) struct ArcConfig {
     pub(crate) phab_uri: Option<String>,
 }
 
-/// Find an arcconfig above the current working directory.
+#[derive(serde::Deserialize)]
+struct ArcRcHostSchema {
+    token: Option<String>,
+}
+
+#[derive(serde::Deserialize, Default)]
+struct ArcRcConfigSchema {
+    default: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct ArcRcSchema {
+    #[serde(default)]
+    hosts: std::collections::BTreeMap<String, ArcRcHostSchema>,
+    #[serde(default)]
+    config: ArcRcConfigSchema,
+}
+
+/// The user-wide `~/.arcrc` with the credentials `arc install-certificate` stores.
+pub(crate) struct ArcRc {
+    schema: ArcRcSchema,
+}
+
+/// Normalize `https://host/` and `https://host/api/` to the same form.
+fn host_key(uri: &str) -> &str {
+    let uri = uri.trim_end_matches('/');
+    uri.strip_suffix("/api").unwrap_or(uri).trim_end_matches('/')
+}
+
+impl ArcRc {
+    /// The Phabricator address, if configured as the default or if there's only one host.
+    pub(crate) fn phab_uri(&self) -> Option<String> {
+        if let Some(default) = &self.schema.config.default {
+            return Some(String::from(host_key(default)));
+        }
+        let mut hosts = self.schema.hosts.keys();
+        match (hosts.next(), hosts.next()) {
+            (Some(host), None) => Some(String::from(host_key(host))),
+            _ => None,
+        }
+    }
+
+    /// The conduit token stored for the Phabricator at `phab_uri`.
+    pub(crate) fn token(&self, phab_uri: &str) -> Option<String> {
+        self.schema.hosts.iter()
+            .find(|(host, _)| host_key(host) == host_key(phab_uri))
+            .and_then(|(_, host)| host.token.clone())
+    }
+}
+
+/// Load the `~/.arcrc`, if there is one.
+pub(crate) fn arcrc() -> Result<Option<ArcRc>, Error> {
+    let home = match std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")) {
+        Some(home) => PathBuf::from(home),
+        None => return Ok(None),
+    };
+    let file_name = home.join(".arcrc");
+    let contents = match std::fs::read_to_string(&file_name) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::OpenArcConfig(e, file_name)),
+    };
+    let schema = serde_json::from_str(&strip_comments(&contents))
+        .map_err(|e| Error::ParseArcRc(e, file_name))?;
+    Ok(Some(ArcRc { schema }))
+}
+
+/// Find an arcconfig above `start`, or the current working directory.
 ///
 /// The expectation that there's `.arcconfig` at the repository root with `repository.callsign`
-/// setting in it.
-pub(crate) fn find() -> Result<ArcConfig, Error> {
-    let mut cwd = std::env::current_dir().map_err(Error::CurrentDir)?;
+/// setting in it. If there is none, `start` is used as the repository root instead.
+pub(crate) fn find(start: Option<&Path>) -> Result<ArcConfig, Error> {
+    let start = match start {
+        Some(start) => start.to_path_buf(),
+        None => std::env::current_dir().map_err(Error::CurrentDir)?,
+    };
+    let mut cwd = start.clone();
+    let not_found = || Ok(ArcConfig { location: start.clone(), phab_uri: None });
     loop {
         let file_name = cwd.join(".arcconfig");
-        let mut file = match std::fs::File::open(&file_name) {
-            Ok(f) => f,
+        let contents = match std::fs::read_to_string(&file_name) {
+            Ok(c) => c,
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
                 if !cwd.pop() {
-                    return Err(Error::FindArcConfig);
+                    return not_found();
                 }
                 continue;
             },
             Err(e) => return Err(Error::OpenArcConfig(e, file_name)),
         };
-        if let Ok(c) = serde_json::from_reader::<_, ArcConfigSchema>(&mut file) {
+        let value: serde_json::Value = serde_json::from_str(&strip_comments(&contents))
+            .map_err(|e| Error::ParseArcConfig(e, file_name))?;
+        // A `.arcconfig` without the callsign is not the one at the repository root.
+        if let Ok(c) = serde_json::from_value::<ArcConfigSchema>(value) {
             return Ok(ArcConfig {
                 location: cwd,
                 phab_uri: c.phab_uri,
             });
         }
         if !cwd.pop() {
-            return Err(Error::FindArcConfig);
+            return not_found();
+        }
+    }
+}
+
+/// Remove the comments and trailing commas Phabricator's own parser tolerates, but JSON doesn't.
+fn strip_comments(json: &str) -> String {
+    let mut result = String::with_capacity(json.len());
+    let mut chars = json.chars().peekable();
+    // Index into `result` of a comma that would be trailing if followed by `}` or `]`.
+    let mut pending_comma = None;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                pending_comma = None;
+                result.push(c);
+                while let Some(c) = chars.next() {
+                    result.push(c);
+                    match c {
+                        '\\' => result.extend(chars.next()),
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut prev = '\0';
+                for c in &mut chars {
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+            }
+            ',' => {
+                pending_comma = Some(result.len());
+                result.push(c);
+            }
+            '}' | ']' => {
+                if let Some(idx) = pending_comma.take() {
+                    result.replace_range(idx..idx + 1, " ");
+                }
+                result.push(c);
+            }
+            c if c.is_whitespace() => result.push(c),
+            c => {
+                pending_comma = None;
+                result.push(c);
+            }
         }
     }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::strip_comments;
+
+    fn parse(json: &str) -> serde_json::Value {
+        serde_json::from_str(&strip_comments(json)).unwrap()
+    }
+
+    #[test]
+    fn strips_comments() {
+        let value = parse(r#"{
+            // The URI itself contains a `//`.
+            "phabricator.uri": "https://phabricator.example.com/", /* trailing */
+            "quoted": "a \"// not a comment\" \\",
+            /* a comment
+               over lines */ "repository.callsign": "X"
+        }"#);
+        assert_eq!(value, serde_json::json!({
+            "phabricator.uri": "https://phabricator.example.com/",
+            "quoted": "a \"// not a comment\" \\",
+            "repository.callsign": "X",
+        }));
+    }
+
+    #[test]
+    fn strips_trailing_commas() {
+        let value = parse(r#"{
+            "list": [1, 2, // the last
+            ],
+            "object": {"a": ",]", /* the last */},
+            "empty": [],
+        }"#);
+        assert_eq!(value, serde_json::json!({
+            "list": [1, 2],
+            "object": {"a": ",]"},
+            "empty": [],
+        }));
+    }
 }
@@ -0,0 +1,129 @@
+use std::path::{Path, PathBuf};
+
+/// The cargo configuration files that apply when cargo is run in `cwd`, from the most specific.
+///
+/// Cargo looks in the `.cargo` directory of `cwd` and each of its parents, then in `CARGO_HOME`.
+fn config_files(cwd: &Path, cargo_home: Option<PathBuf>) -> Vec<PathBuf> {
+    let mut dirs = cwd.ancestors().map(|dir| dir.join(".cargo")).collect::<Vec<_>>();
+    dirs.extend(cargo_home);
+    let mut files = Vec::new();
+    for dir in dirs {
+        for name in &["config.toml", "config"] {
+            let file = dir.join(name);
+            if file.is_file() && !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+    files
+}
+
+/// The `target.<triple>` or `target.'cfg(..)'` tables of the cargo configuration in `cwd` that
+/// set `rustflags`, by the part of the key after `target.`.
+///
+/// Cargo uses these instead of `build.rustflags` for the targets they apply to.
+pub(crate) fn target_rustflags(cwd: &Path) -> Vec<String> {
+    let cargo_home = std::env::var_os("CARGO_HOME").map(PathBuf::from).or_else(|| {
+        let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+        Some(Path::new(&home).join(".cargo"))
+    });
+    let mut keys = Vec::new();
+    for file in config_files(cwd, cargo_home) {
+        // Unreadable files fail the cargo invocations themselves, with a better error.
+        let contents = std::fs::read_to_string(file).unwrap_or_default();
+        for key in parse_target_rustflags(&contents) {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+    }
+    keys
+}
+
+/// The environment variable that sets the `rustflags` of the `target` triple.
+pub(crate) fn target_rustflags_var(target: &str) -> String {
+    format!("CARGO_TARGET_{}_RUSTFLAGS", target.to_uppercase().replace(['-', '.'], "_"))
+}
+
+/// The target keys with `rustflags` in the TOML `config`, as either a `rustflags` key in a
+/// `[target.KEY]` table, or a `target.KEY.rustflags` key.
+///
+/// Only looks at the lines as cargo configuration is usually written, rather than parsing all of
+/// TOML.
+fn parse_target_rustflags(config: &str) -> Vec<String> {
+    let mut keys = Vec::new();
+    let mut table = None;
+    for line in config.lines() {
+        let line = line.trim();
+        if let Some(header) = line.strip_prefix('[') {
+            let header = header.split(']').next().unwrap_or_default().trim();
+            table = header.strip_prefix("target.").map(|key| unquote(key.trim()));
+            continue;
+        }
+        let name = match line.split_once('=') {
+            Some((name, _)) => name.trim(),
+            None => continue,
+        };
+        let key = match &table {
+            Some(key) if name == "rustflags" => key.clone(),
+            None => match name.strip_prefix("target.").and_then(|k| k.strip_suffix(".rustflags")) {
+                Some(key) => unquote(key.trim()),
+                None => continue,
+            },
+            Some(_) => continue,
+        };
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+    keys
+}
+
+/// A TOML key without the quotes around it, if any.
+fn unquote(key: &str) -> String {
+    for quote in &['\'', '"'] {
+        if let Some(key) = key.strip_prefix(*quote).and_then(|k| k.strip_suffix(*quote)) {
+            return String::from(key);
+        }
+    }
+    String::from(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_target_rustflags, target_rustflags_var};
+
+    #[test]
+    fn finds_target_rustflags() {
+        let config = r#"
+[build]
+rustflags = ["--cfg", "build"]
+
+[target.x86_64-unknown-linux-gnu]
+linker = "clang"
+rustflags = ["-C", "link-arg=-fuse-ld=lld"]
+
+[target.'cfg(target_os = "macos")']
+rustflags = ["--cfg", "mac"]
+
+[target.aarch64-unknown-linux-gnu]
+runner = "qemu-aarch64"
+
+[env]
+rustflags = "not a flag"
+"#;
+        assert_eq!(
+            parse_target_rustflags(config),
+            ["x86_64-unknown-linux-gnu", r#"cfg(target_os = "macos")"#]
+        );
+        assert_eq!(
+            parse_target_rustflags("target.\"cfg(unix)\".rustflags = [\"--cfg\", \"unix\"]"),
+            ["cfg(unix)"]
+        );
+        assert!(parse_target_rustflags("[build]\nrustflags = []").is_empty());
+        assert_eq!(
+            target_rustflags_var("x86_64-unknown-linux-gnu"),
+            "CARGO_TARGET_X86_64_UNKNOWN_LINUX_GNU_RUSTFLAGS"
+        );
+    }
+}
@@ -1,5 +1,7 @@
-use tokio::process::Command;
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::fmt::Write;
 use futures::StreamExt;
 use crate::jsonl::FilterReportedExt;
 
@@ -9,11 +11,35 @@ pub(crate) enum Error {
     PublishLints(#[source] crate::phab::Error),
     #[error("could not get command output")]
     CommandOutput(#[source] crate::jsonl::Error),
+    #[error("could not upload the build artifacts")]
+    UploadArtifact(#[source] crate::phab::Error),
+    #[error("found {0} lint(s) with severity {1} or higher")]
+    FailOn(usize, crate::phab::Severity),
+    #[error("could not find the workspace root")]
+    Metadata(#[source] crate::metadata::Error),
+    #[error(transparent)]
+    Deadline(#[from] crate::DeadlineError),
+    #[error(transparent)]
+    Interrupted(#[from] crate::InterruptedError),
 }
 
-#[derive(serde::Deserialize)]
+impl crate::ClassifyError for Error {
+    fn kind(&self) -> crate::ErrorKind {
+        match self {
+            Error::PublishLints(e) => e.kind(),
+            Error::CommandOutput(e) => e.kind(),
+            Error::UploadArtifact(e) => e.kind(),
+            Error::FailOn(..) => crate::ErrorKind::User,
+            Error::Metadata(e) => e.kind(),
+            Error::Deadline(e) => e.kind(),
+            Error::Interrupted(e) => e.kind(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
 #[serde(rename_all="kebab-case")]
-enum LintLevel {
+pub(crate) enum LintLevel {
     Error,
     Warning,
     Note,
@@ -33,12 +59,51 @@ impl From<LintLevel> for crate::phab::Severity {
     }
 }
 
+impl std::str::FromStr for LintLevel {
+    type Err = String;
+    fn from_str(level: &str) -> Result<Self, String> {
+        match level {
+            "error" => Ok(Self::Error),
+            "warning" => Ok(Self::Warning),
+            "note" => Ok(Self::Note),
+            "help" => Ok(Self::Help),
+            "failure-note" => Ok(Self::FailureNote),
+            _ => Err(format!(
+                "`{}` is not a lint level, expected one of error, warning, note, help or \
+                 failure-note",
+                level
+            )),
+        }
+    }
+}
+
 #[derive(serde::Deserialize)]
 struct SpanSchema {
+    byte_start: usize,
+    byte_end: usize,
     column_start: u64,
     line_start: u64,
+    line_end: u64,
     file_name: String,
     is_primary: bool,
+    label: Option<String>,
+    #[serde(default)]
+    text: Vec<SpanTextSchema>,
+    suggested_replacement: Option<String>,
+    suggestion_applicability: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct SpanTextSchema {
+    text: String,
+    highlight_start: usize,
+    highlight_end: usize,
+}
+
+#[derive(serde::Deserialize)]
+struct ChildSchema {
+    message: String,
+    spans: Vec<SpanSchema>,
 }
 
 #[derive(serde::Deserialize)]
@@ -53,79 +118,674 @@ struct MessageSchema {
     code: Option<CodeSchema>,
     spans: Vec<SpanSchema>,
     message: String,
+    #[serde(default)]
+    children: Vec<ChildSchema>,
 }
 
 #[derive(serde::Deserialize)]
 struct TargetSchema {
     src_path: String,
+    name: String,
+    kind: Vec<String>,
+}
+
+impl TargetSchema {
+    /// E.g. "lib `foo`" or "test `integration`".
+    ///
+    /// A library compiled for its unit tests is still a `lib` target, cargo does not say which
+    /// of the two a message is from.
+    fn describe(&self) -> String {
+        let kind = match self.kind.first().map(|k| &k[..]) {
+            Some("rlib") | Some("dylib") | Some("cdylib") | Some("staticlib")
+            | Some("proc-macro") => "lib",
+            Some("custom-build") => "build script",
+            Some(kind) => kind,
+            None => "target",
+        };
+        format!("{} `{}`", kind, self.name)
+    }
 }
 
 #[derive(serde::Deserialize)]
-struct LintSchema {
+pub(crate) struct LintSchema {
     message: MessageSchema,
     target: TargetSchema,
+    package_id: String,
+}
+
+#[derive(serde::Deserialize)]
+struct ArtifactSchema {
+    package_id: String,
+    target: TargetSchema,
+    executable: Option<PathBuf>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+enum CargoMessageSchema {
+    CompilerMessage(LintSchema),
+    CompilerArtifact(ArtifactSchema),
+    #[serde(other)]
+    Other,
+}
+
+type LintKey = (Cow<'static, str>, Cow<'static, Path>, Option<u64>, Option<u64>, Cow<'static, str>);
+
+/// The lints from the `compiler-message`s in cargo's output.
+///
+/// The same lint is emitted once for every target a file is compiled as part of (e.g. lib and
+/// test), so only the first occurrence of each is kept. The targets it was emitted for are listed
+/// in its description, as some lints only apply to some of them (e.g. due to `#[cfg(test)]`),
+/// along with how many times it occurred.
+///
+/// The machine applicable suggestions of the lints are collected as `fixes`, by the file they are
+/// in and its path relative to the repository.
+#[derive(Default)]
+pub(crate) struct Lints {
+    pub(crate) lints: Vec<crate::phab::Lint>,
+    fixes: std::collections::BTreeMap<(PathBuf, PathBuf), Vec<crate::diff::Edit>>,
+    seen: std::collections::HashMap<LintKey, usize>,
+    seen_targets: std::collections::HashSet<(usize, String)>,
+    occurrences: std::collections::HashMap<usize, usize>,
+    /// The default levels of the rustc lints seen so far, by their code.
+    default_levels: std::collections::HashMap<String, &'static str>,
+}
+
+/// The note on how many times a lint occurred, which ends its description.
+fn occurrences_note(count: usize) -> String {
+    if count > 1 {
+        format!(" (occurred {} times)", count)
+    } else {
+        String::new()
+    }
+}
+
+/// Remove the ANSI escape sequences colored terminal output contains.
+fn strip_ansi(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            // Control sequences, e.g. colors: `ESC [ parameters final-byte`.
+            Some('[') => {
+                while chars.next_if(|c| !('\x40'..='\x7e').contains(c)).is_some() {}
+                chars.next();
+            }
+            // Operating system commands, e.g. hyperlinks, terminated by `BEL` or `ESC \`.
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Describe the suggestions rustc is confident enough to apply automatically as diffs.
+fn describe_suggestions(children: &[ChildSchema]) -> String {
+    let mut description = String::new();
+    for child in children {
+        for span in &child.spans {
+            let replacement = match (&span.suggested_replacement, &span.suggestion_applicability) {
+                (Some(replacement), Some(applicability)) if applicability == "MachineApplicable" => {
+                    replacement
+                }
+                _ => continue,
+            };
+            write!(
+                &mut description,
+                "\n\n{} ({}:{}):\n```lang=diff\n",
+                child.message, span.file_name, span.line_start
+            ).expect("can't fail");
+            match &span.text[..] {
+                [text] if span.line_start == span.line_end => {
+                    // The highlight columns are 1-based and count characters, not bytes.
+                    let prefix: String = text.text.chars().take(text.highlight_start - 1).collect();
+                    let suffix: String = text.text.chars().skip(text.highlight_end - 1).collect();
+                    writeln!(&mut description, "-{}", text.text).expect("can't fail");
+                    writeln!(&mut description, "+{}{}{}", prefix, replacement, suffix)
+                        .expect("can't fail");
+                }
+                texts => {
+                    for text in texts {
+                        writeln!(&mut description, "-{}", text.text).expect("can't fail");
+                    }
+                    for line in replacement.lines() {
+                        writeln!(&mut description, "+{}", line).expect("can't fail");
+                    }
+                }
+            }
+            description.push_str("```");
+        }
+    }
+    description
+}
+
+/// The `NAME@VERSION` cargo refers to the package as in its human readable output.
+fn package_spec(package_id: &str) -> String {
+    format!(
+        "{}@{}",
+        crate::metadata::package_name(package_id),
+        crate::metadata::package_version(package_id)
+    )
+}
+
+/// The package and message of a `warning: NAME@VERSION: MESSAGE` line cargo outputs for the
+/// `cargo:warning`s of build scripts.
+fn build_script_warning(line: &str) -> Option<(&str, &str)> {
+    let (package, message) = line.strip_prefix("warning: ")?.split_once(": ")?;
+    if !package.contains('@') || package.contains(' ') {
+        return None;
+    }
+    Some((package, message))
+}
+
+/// Produce the Phabricator lint code for a rustc or clippy lint.
+fn lint_code(code: &str) -> String {
+    match code.strip_prefix("clippy::") {
+        Some(clippy_lint) => format!("CLIPPY{}", clippy_lint),
+        None => format!("CHECK{}", code),
+    }
+}
+
+/// The default level of a rustc lint, from the note rustc adds to the first occurrence of a lint
+/// that is at that level.
+///
+/// `forbid` lints are documented with the `deny` ones.
+fn default_level(children: &[ChildSchema]) -> Option<&'static str> {
+    // E.g. "`#[warn(unused_variables)]` on by default".
+    let level = children.iter()
+        .filter(|child| child.message.ends_with("on by default"))
+        .find_map(|child| child.message.strip_prefix("`#[")?.split('(').next())?;
+    match level {
+        "warn" => Some("warn"),
+        "deny" | "forbid" => Some("deny"),
+        _ => None,
+    }
+}
+
+/// The documentation of a rustc error code, or of a rustc or clippy lint.
+///
+/// The rustc lints are documented on a page for their default level. Without it, e.g. for the
+/// lints that are not on by default, the list of all of the lints is linked instead.
+fn lint_docs_url(code: &str, default_level: Option<&str>) -> Option<String> {
+    if let Some(clippy_lint) = code.strip_prefix("clippy::") {
+        return Some(format!(
+            "https://rust-lang.github.io/rust-clippy/master/index.html#{}",
+            clippy_lint
+        ));
+    }
+    let is_error_code = code.len() == 5 && code.starts_with('E')
+        && code[1..].bytes().all(|b| b.is_ascii_digit());
+    if is_error_code {
+        return Some(format!("https://doc.rust-lang.org/error_codes/{}.html", code));
+    }
+    // The lints of other tools, like rustdoc, are not in the rustc documentation.
+    if code.contains("::") {
+        return None;
+    }
+    Some(match default_level {
+        Some(level) => format!(
+            "https://doc.rust-lang.org/rustc/lints/listing/{}-by-default.html#{}",
+            level,
+            code.replace('_', "-")
+        ),
+        None => String::from("https://doc.rust-lang.org/rustc/lints/listing/index.html"),
+    })
 }
 
 impl crate::Context {
+    /// The severity to report lints of this level with, taking `--severity-map` into account.
+    fn severity(&self, level: LintLevel) -> crate::phab::Severity {
+        self.severity_map.get(&level).copied().unwrap_or_else(|| level.into())
+    }
+
+    /// List the locations the lint refers to besides `location`, as only one of them can be
+    /// attached to a Harbormaster lint.
+    fn describe_other_spans(&self, spans: &[SpanSchema], location: Option<&SpanSchema>) -> String {
+        let mut description = String::new();
+        for span in spans {
+            if location.is_some_and(|l| std::ptr::eq(l, span)) {
+                continue;
+            }
+            if description.is_empty() {
+                description.push_str("\n\nOther locations:");
+            }
+            let path = self.repo_relative(Path::new(&span.file_name));
+            write!(&mut description, "\n* `{}:{}`", path.display(), span.line_start)
+                .expect("can't fail");
+            if let Some(label) = span.label.as_deref().filter(|l| !l.is_empty()) {
+                write!(&mut description, ": {}", label).expect("can't fail");
+            }
+        }
+        description
+    }
+
     pub(crate) async fn check(&self, subcommand: &str, args: &clap::ArgMatches<'_>) -> Result<(), Error> {
-        let mut lints = Vec::with_capacity(64);
-        let result = self.check_inner(&mut lints, subcommand, args).await;
-        if !lints.is_empty() {
-            self.publish_work(
-                &lints,
+        let mut lints = Lints::default();
+        let mut artifacts = Vec::new();
+        let result = self.with_heartbeat(
+            self.check_inner(&mut lints, &mut artifacts, subcommand, args)
+        ).await;
+        if !lints.lints.is_empty() {
+            let published = self.publish_work(
+                None,
+                &lints.lints,
                 &[],
             ).await.map_err(Error::PublishLints)?;
+            self.report_published(&published);
+        }
+        result?;
+        if args.is_present("upload_artifacts") {
+            for executable in &artifacts {
+                let key = executable.file_name()
+                    .map(|n| n.to_string_lossy())
+                    .unwrap_or_else(|| executable.to_string_lossy());
+                self.create_artifact(&key, executable).await.map_err(Error::UploadArtifact)?;
+            }
+        }
+        if args.is_present("clippy_fixes_artifact") {
+            let patch = self.fixes_patch(&lints);
+            if !patch.is_empty() {
+                let key = "Suggested clippy fixes";
+                self.create_data_artifact(key, "clippy-fixes.diff", patch.as_bytes())
+                    .await
+                    .map_err(Error::UploadArtifact)?;
+            }
+        }
+        if let Some(threshold) = self.fail_on {
+            let count = lints.lints.iter().filter(|l| l.severity.rank() >= threshold.rank()).count();
+            if count > 0 {
+                return Err(Error::FailOn(count, threshold));
+            }
+        }
+        Ok(())
+    }
+
+    async fn check_inner(
+        &self,
+        lints: &mut Lints,
+        artifacts: &mut Vec<PathBuf>,
+        subcommand: &str,
+        args: &clap::ArgMatches<'_>,
+    ) -> Result<(), Error> {
+        // The paths in the diagnostics are relative to the workspace root.
+        self.metadata().await.map_err(Error::Metadata)?;
+        let mut cmd = self.cargo_json_command(subcommand);
+        cmd.args(crate::cargo_feature_args(args));
+        cmd.args(without_ansi_message_format(
+            args.values_of_os("args").into_iter().flatten(),
+            self.log(),
+        ));
+        // The `cargo:warning`s of build scripts are only in the human readable output on stderr.
+        let (values, stderr) = self.get_stdout_json_lines_and_stderr(cmd);
+        let values = values.filter_reported(self.log());
+        let mut build_scripts = std::collections::HashMap::new();
+        let messages = async {
+            futures::pin_mut!(values);
+            while let Some(result) = values.next().await {
+                let lint = match result.map_err(Error::CommandOutput)? {
+                    CargoMessageSchema::CompilerMessage(lint) => lint,
+                    CargoMessageSchema::CompilerArtifact(artifact) => {
+                        if artifact.target.kind.iter().any(|k| k == "custom-build") {
+                            let package = package_spec(&artifact.package_id);
+                            build_scripts.insert(package, artifact.target.src_path);
+                        }
+                        artifacts.extend(artifact.executable);
+                        continue;
+                    }
+                    CargoMessageSchema::Other => continue,
+                };
+                self.add_lint(lints, lint);
+            }
+            Ok(())
+        };
+        let (result, stderr) = futures::join!(messages, stderr);
+        for (package, message) in stderr.iter().filter_map(|l| build_script_warning(l)) {
+            // The warnings of build scripts that did not run again are not repeated, so their
+            // artifact is always there.
+            if let Some(build_script) = build_scripts.get(package) {
+                self.add_build_script_warning(lints, package, build_script, message);
+            }
         }
         result
     }
 
-    async fn check_inner(&self, lints: &mut Vec<crate::phab::Lint>, subcommand: &str, args: &clap::ArgMatches<'_>) -> Result<(), Error> {
-        let mut cmd = Command::new("cargo");
-        cmd.arg(subcommand)
-           .arg("--message-format").arg("json")
-           .kill_on_drop(true);
-        if let Some(args) = args.values_of_os("args") {
-            cmd.args(args);
-        }
-        let values = self.get_reason_json_lines(cmd, "compiler-message").filter_reported();
-        futures::pin_mut!(values);
-        while let Some(result) = values.next().await {
-            let lint: LintSchema = result.map_err(Error::CommandOutput)?;
-            // So far it seems that the only messages where the code is missing are things like `N
-            // warnings emitted`.
-            let code = if let Some(code) = lint.message.code {
-                format!("CHECK{}", code.code)
-            } else {
-                continue;
+    /// Add a `cargo:warning` of the build script of `package` as a lint on the build script.
+    fn add_build_script_warning(
+        &self,
+        lints: &mut Lints,
+        package: &str,
+        build_script: &str,
+        message: &str,
+    ) {
+        let package_name = package.split('@').next().unwrap_or(package);
+        let lint = crate::phab::Lint {
+            name: format!("[{}] {}", package_name, message).into(),
+            code: "BUILDSCRIPT".into(),
+            severity: self.severity(LintLevel::Warning),
+            path: self.repo_relative(Path::new(build_script)).into(),
+            description: Some(format!("Reported by the build script of `{}`", package).into()),
+            line: None,
+            column: None,
+        };
+        if !self.reports(&lint) {
+            return;
+        }
+        self.report_lint(&lint);
+        lints.lints.push(lint);
+    }
+
+    /// Add the lint from a `compiler-message`, unless it was already added for another target.
+    pub(crate) fn add_lint(&self, lints: &mut Lints, lint: LintSchema) {
+        // So far it seems that the only messages where the code is missing are things like `N
+        // warnings emitted`.
+        let (code, docs) = if let Some(code) = &lint.message.code {
+            // rustc only notes the default level on the first occurrence of the lint.
+            let level = match default_level(&lint.message.children) {
+                Some(level) => {
+                    lints.default_levels.insert(code.code.clone(), level);
+                    Some(level)
+                }
+                None => lints.default_levels.get(&code.code).copied(),
             };
-            let description = format!("```\n{}\n```", lint.message.rendered.trim());
-            let lint = match lint.message.spans.iter().find(|s| s.is_primary) {
-                Some(span) => crate::phab::Lint {
-                    name: lint.message.message.into(),
-                    code: code.into(),
-                    severity: lint.message.level.into(),
-                    line: Some(span.line_start),
-                    column: Some(span.column_start),
-                    path: PathBuf::from(&span.file_name).into(),
-                    description: Some(description.into()),
-                },
-                None => {
-                    let filename = PathBuf::from(lint.target.src_path);
-                    let filename = filename.strip_prefix(&self.arcconfig).unwrap_or(&filename);
-                    crate::phab::Lint {
-                        name: lint.message.message.into(),
-                        code: code.into(),
-                        severity: lint.message.level.into(),
-                        line: None,
-                        column: None,
-                        path: PathBuf::from(filename).into(),
-                        description: Some(description.into())
-                    }
-                },
+            (lint_code(&code.code), lint_docs_url(&code.code, level))
+        } else {
+            return;
+        };
+        let target = lint.target.describe();
+        let children = &lint.message.children;
+        // Before the targets, which more are added to the end of.
+        let docs = match docs.filter(|_| self.lint_links) {
+            Some(url) => format!("[[ {} | Documentation ]]\n\n", url),
+            None => String::new(),
+        };
+        // Some diagnostics, e.g. from macro expansions, have no primary span.
+        let spans = &lint.message.spans;
+        let location = spans.iter().find(|s| s.is_primary).or_else(|| spans.first());
+        let description = if self.no_rendered {
+            format!("{}Reported for {}", docs, target)
+        } else {
+            format!(
+                "```\n{}\n```{}{}\n\n{}Reported for {}",
+                strip_ansi(&lint.message.rendered).trim(),
+                describe_suggestions(&lint.message.children),
+                self.describe_other_spans(spans, location),
+                docs,
+                target
+            )
+        };
+        // In a workspace it is otherwise not obvious which package the lint is from.
+        let name = format!(
+            "[{}] {}",
+            crate::metadata::package_name(&lint.package_id),
+            lint.message.message
+        );
+        let lint = match location {
+            Some(span) => crate::phab::Lint {
+                name: name.into(),
+                code: code.into(),
+                severity: self.severity(lint.message.level),
+                line: Some(span.line_start),
+                column: Some(span_column(span)),
+                path: self.repo_relative(Path::new(&span.file_name)).into(),
+                description: Some(description.into()),
+            },
+            None => crate::phab::Lint {
+                name: name.into(),
+                code: code.into(),
+                severity: self.severity(lint.message.level),
+                line: None,
+                column: None,
+                path: self.repo_relative(Path::new(&lint.target.src_path)).into(),
+                description: Some(description.into())
+            },
+        };
+        if !self.reports(&lint) {
+            return;
+        }
+        let key = (
+            lint.code.clone(),
+            lint.path.clone(),
+            lint.line,
+            lint.column,
+            lint.name.clone(),
+        );
+        if let Some(&index) = lints.seen.get(&key) {
+            let count = lints.occurrences.entry(index).or_insert(1);
+            *count += 1;
+            let new_target = lints.seen_targets.insert((index, target.clone()));
+            let existing: &mut crate::phab::Lint = &mut lints.lints[index];
+            if let Some(description) = &mut existing.description {
+                let description = description.to_mut();
+                description.truncate(description.len() - occurrences_note(*count - 1).len());
+                if new_target {
+                    description.push_str(&format!(", {}", target));
+                }
+                description.push_str(&occurrences_note(*count));
+            }
+            return;
+        }
+        lints.seen.insert(key, lints.lints.len());
+        lints.seen_targets.insert((lints.lints.len(), target));
+        self.report_lint(&lint);
+        lints.lints.push(lint);
+        for span in children.iter().flat_map(|child| &child.spans) {
+            let replacement = match (&span.suggested_replacement, &span.suggestion_applicability) {
+                (Some(replacement), Some(applicability)) if applicability == "MachineApplicable" => {
+                    replacement
+                }
+                _ => continue,
             };
-            lint.report();
-            lints.push(lint);
+            let file = self.workspace_path(Path::new(&span.file_name)).into_owned();
+            let path = match self.try_repo_relative(&file) {
+                Some(path) => path,
+                None => continue,
+            };
+            lints.fixes.entry((path, file)).or_default().push(crate::diff::Edit {
+                range: span.byte_start..span.byte_end,
+                replacement: replacement.clone(),
+            });
         }
-        Ok(())
+    }
+
+    /// A patch applying the machine applicable suggestions of the lints, like `cargo fix` would.
+    fn fixes_patch(&self, lints: &Lints) -> String {
+        let mut patch = String::new();
+        for ((path, file), edits) in &lints.fixes {
+            match std::fs::read_to_string(file) {
+                Ok(original) => patch.push_str(&crate::diff::unified(path, &original, edits)),
+                Err(e) => self.warn(format_args!(
+                    "leaving the suggestions for {} out of the patch, it could not be read: {}",
+                    path.display(),
+                    e
+                )),
+            }
+        }
+        patch
+    }
+}
+
+/// The cargo arguments, without any `--message-format` asking for colored diagnostics.
+///
+/// Arguments after a `--` are for the compiler or the harness, and are kept as they are.
+pub(crate) fn without_ansi_message_format<T: AsRef<OsStr>>(
+    args: impl IntoIterator<Item=T>,
+    log: crate::phab::Log,
+) -> Vec<T> {
+    let mut args = args.into_iter();
+    let mut kept = Vec::new();
+    while let Some(arg) = args.next() {
+        let text = arg.as_ref().to_string_lossy().into_owned();
+        if text == "--" {
+            kept.push(arg);
+            kept.extend(args);
+            break;
+        }
+        let (format, value) = if text == "--message-format" {
+            let value = args.next();
+            (value.as_ref().map(|v| v.as_ref().to_string_lossy().into_owned()), value)
+        } else if let Some(format) = text.strip_prefix("--message-format=") {
+            (Some(format.to_owned()), None)
+        } else {
+            (None, None)
+        };
+        if let Some(format) = format.filter(|f| f.contains("ansi")) {
+            log.warn(format_args!(
+                "ignoring `--message-format {}`, the diagnostics are published without colors",
+                format
+            ));
+            continue;
+        }
+        kept.push(arg);
+        kept.extend(value);
+    }
+    kept
+}
+
+/// Convert a 1-based character column within `line` to the 1-based byte column.
+fn byte_column(line: &str, column: u64) -> u64 {
+    let prefix_len: usize = line.chars().take(column.saturating_sub(1) as usize)
+        .map(char::len_utf8).sum();
+    prefix_len as u64 + 1
+}
+
+/// rustc counts columns in characters (a tab being one), while Phabricator, like arcanist's own
+/// linters, counts them in bytes.
+fn span_column(span: &SpanSchema) -> u64 {
+    match span.text.first() {
+        Some(text) => byte_column(&text.text, span.column_start),
+        None => span.column_start,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        build_script_warning, byte_column, default_level, lint_docs_url, strip_ansi,
+        without_ansi_message_format, ChildSchema, Lints,
+    };
+
+    #[test]
+    fn strips_colors_from_rendered() {
+        let rendered = "\x1b[0m\x1b[1m\x1b[33mwarning\x1b[0m\x1b[0m\x1b[1m: unused variable: `x`\x1b[0m\n\
+                        \x1b[0m \x1b[0m\x1b[0m\x1b[1m\x1b[38;5;12m--> \x1b[0m\x1b[0msrc/main.rs:2:9\x1b[0m";
+        assert_eq!(
+            strip_ansi(rendered),
+            "warning: unused variable: `x`\n --> src/main.rs:2:9"
+        );
+    }
+
+    #[test]
+    fn strips_hyperlinks() {
+        let rendered = "see \x1b]8;;https://example.com\x07docs\x1b]8;;\x1b\\ for details";
+        assert_eq!(strip_ansi(rendered), "see docs for details");
+    }
+
+    #[test]
+    fn keeps_plain_text() {
+        assert_eq!(strip_ansi("error[E0308]: mismatched types"), "error[E0308]: mismatched types");
+    }
+
+    #[test]
+    fn drops_colored_message_formats() {
+        let args = [
+            "--message-format", "json-diagnostic-rendered-ansi", "--message-format=short",
+            "--message-format=json-diagnostic-rendered-ansi", "--release",
+            "--", "--message-format", "ansi",
+        ];
+        let log = crate::phab::Log::new(crate::Verbosity::Quiet, crate::phab::OutputFormat::Human);
+        assert_eq!(
+            without_ansi_message_format(args.iter(), log),
+            ["--message-format=short", "--release", "--", "--message-format", "ansi"].iter()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn converts_columns_to_bytes() {
+        let line = "\tlet café = \"ü\";";
+        // `=` is the 11th character, but `é` takes two bytes.
+        assert_eq!(byte_column(line, 11), 12);
+        // The tab counts as a single character and a single byte.
+        assert_eq!(byte_column(line, 14), 15);
+        assert_eq!(byte_column(line, 1), 1);
+        assert_eq!(byte_column("abc", 3), 3);
+    }
+
+    #[test]
+    fn parses_build_script_warnings() {
+        assert_eq!(
+            build_script_warning("warning: demo@0.1.0: something is off: badly"),
+            Some(("demo@0.1.0", "something is off: badly"))
+        );
+        assert_eq!(build_script_warning("warning: unused manifest key: package.foo"), None);
+        assert_eq!(build_script_warning("   Compiling demo v0.1.0"), None);
+    }
+
+    #[test]
+    fn links_lint_docs() {
+        let note = |message: &str| ChildSchema { message: message.into(), spans: vec![] };
+        assert_eq!(
+            lint_docs_url("clippy::needless_return", None).as_deref(),
+            Some("https://rust-lang.github.io/rust-clippy/master/index.html#needless_return")
+        );
+        assert_eq!(
+            lint_docs_url("E0308", None).as_deref(),
+            Some("https://doc.rust-lang.org/error_codes/E0308.html")
+        );
+        assert_eq!(default_level(&[note("`#[warn(dead_code)]` on by default")]), Some("warn"));
+        assert_eq!(default_level(&[note("`#[forbid(x)]` on by default")]), Some("deny"));
+        assert_eq!(
+            lint_docs_url("dead_code", Some("warn")).as_deref(),
+            Some("https://doc.rust-lang.org/rustc/lints/listing/warn-by-default.html#dead-code")
+        );
+        // Not at the default level, which is what the documentation is organized by.
+        assert_eq!(default_level(&[note("the lint level is defined here")]), None);
+        assert_eq!(
+            lint_docs_url("unused_results", None).as_deref(),
+            Some("https://doc.rust-lang.org/rustc/lints/listing/index.html")
+        );
+        assert_eq!(lint_docs_url("rustdoc::broken_intra_doc_links", None), None);
+    }
+
+    #[test]
+    fn links_lint_docs_of_later_occurrences() {
+        let ctxt = crate::Context { lint_links: true, no_rendered: true, ..Default::default() };
+        let mut lints = Lints::default();
+        let lint = |message: &str, children: serde_json::Value| serde_json::from_value(
+            serde_json::json!({
+                "message": {
+                    "rendered": "",
+                    "level": "warning",
+                    "code": { "code": "dead_code" },
+                    "spans": [],
+                    "message": message,
+                    "children": children,
+                },
+                "target": { "src_path": "src/lib.rs", "name": "demo", "kind": ["lib"] },
+                "package_id": "demo 0.1.0 (path+file:///demo)",
+            })
+        ).unwrap();
+        let note = serde_json::json!([{
+            "message": "`#[warn(dead_code)]` on by default",
+            "spans": [],
+        }]);
+        ctxt.add_lint(&mut lints, lint("function `a` is never used", note));
+        ctxt.add_lint(&mut lints, lint("function `b` is never used", serde_json::json!([])));
+        for lint in &lints.lints {
+            assert!(lint.description.as_deref().unwrap().contains(
+                "https://doc.rust-lang.org/rustc/lints/listing/warn-by-default.html#dead-code"
+            ));
+        }
+        assert_eq!(lints.lints.len(), 2);
     }
 }
@@ -0,0 +1,337 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum Error {
+    #[error("could not read the --diff {1:?}")]
+    Read(#[source] std::io::Error, PathBuf),
+    #[error("could not run git, make sure it is in PATH")]
+    Run(#[source] std::io::Error),
+    #[error("`git {0}` failed with {1}")]
+    ExitStatus(&'static str, std::process::ExitStatus),
+}
+
+impl crate::ClassifyError for Error {
+    fn kind(&self) -> crate::ErrorKind {
+        match self {
+            Error::Read(..) => crate::ErrorKind::User,
+            Error::Run(_) => crate::ErrorKind::User,
+            Error::ExitStatus(..) => crate::ErrorKind::User,
+        }
+    }
+}
+
+/// The lines added or changed by a diff, by the path of the file they are in.
+#[derive(Default, Debug)]
+pub(crate) struct ChangedLines {
+    files: HashMap<PathBuf, Vec<u64>>,
+    /// Files that are new in their entirety, like the ones git does not track yet.
+    new_files: HashSet<PathBuf>,
+}
+
+impl ChangedLines {
+    /// Collect the changed lines out of a unified diff.
+    ///
+    /// The `b/` prefix `git diff` adds to the paths is removed. Lines are numbered as they are
+    /// after the change, removed lines are not included.
+    pub(crate) fn parse(diff: &str) -> ChangedLines {
+        let mut changed = ChangedLines::default();
+        let mut file: Option<&mut Vec<u64>> = None;
+        let mut line = 0;
+        // The lines of the current hunk that are yet to come, from before and after the change.
+        let (mut old_left, mut new_left) = (0u64, 0u64);
+        for text in diff.lines() {
+            if old_left > 0 || new_left > 0 {
+                if text.starts_with('+') {
+                    if let Some(lines) = &mut file {
+                        lines.push(line);
+                    }
+                    line += 1;
+                    new_left = new_left.saturating_sub(1);
+                } else if text.starts_with('-') {
+                    old_left = old_left.saturating_sub(1);
+                } else if !text.starts_with('\\') {
+                    line += 1;
+                    old_left = old_left.saturating_sub(1);
+                    new_left = new_left.saturating_sub(1);
+                }
+            } else if let Some(path) = text.strip_prefix("+++ ") {
+                // Some tools follow the path with a tab and the modification time.
+                let path = path.split('\t').next().unwrap_or(path);
+                file = if path == "/dev/null" {
+                    None
+                } else {
+                    let path = path.strip_prefix("b/").unwrap_or(path);
+                    Some(changed.files.entry(path.into()).or_insert_with(Vec::new))
+                };
+            } else if let Some(hunk) = text.strip_prefix("@@ ") {
+                // `@@ -OLD[,COUNT] +NEW[,COUNT] @@`, where the count is 1 if it is left out.
+                let mut ranges = hunk.split(' ').take(2).map(|range| {
+                    let numbers = range.get(1..).unwrap_or("");
+                    let mut parts = numbers.split(',').map(|n| n.parse::<u64>().unwrap_or(0));
+                    (parts.next().unwrap_or(0), parts.next().unwrap_or(1))
+                });
+                let (_, old_count) = ranges.next().unwrap_or((0, 0));
+                let (new_start, new_count) = ranges.next().unwrap_or((0, 0));
+                line = new_start;
+                old_left = old_count;
+                new_left = new_count;
+            }
+        }
+        changed
+    }
+
+    /// Whether the `line` of the file at `path` has changed.
+    pub(crate) fn contains(&self, path: &Path, line: u64) -> bool {
+        self.new_files.contains(path)
+            || self.files.get(path).is_some_and(|lines| lines.contains(&line))
+    }
+}
+
+/// A replacement of the bytes in `range` of a file, like rustc suggests them.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) struct Edit {
+    pub(crate) range: std::ops::Range<usize>,
+    pub(crate) replacement: String,
+}
+
+/// The lines around the changes that are included in the hunks, like `diff -u` does.
+const CONTEXT: usize = 3;
+
+/// A unified diff applying the `edits` to the `original` contents of the file at `path`, or an
+/// empty string if there are no changes.
+///
+/// The edits are applied in the order of the bytes they replace. Edits overlapping one that was
+/// already applied, or out of the bounds of the file, are left out, as `cargo fix` would do.
+pub(crate) fn unified(path: &Path, original: &str, edits: &[Edit]) -> String {
+    let mut edits = edits.iter()
+        .filter(|e| original.get(e.range.clone()).is_some())
+        .collect::<Vec<_>>();
+    edits.sort_by_key(|e| (e.range.start, e.range.end));
+    edits.dedup();
+    let mut applied = Vec::<&Edit>::new();
+    for edit in edits {
+        if applied.last().is_some_and(|last| edit.range.start < last.range.end) {
+            continue;
+        }
+        applied.push(edit);
+    }
+
+    let mut line_starts = vec![0];
+    line_starts.extend(original.match_indices('\n').map(|(i, _)| i + 1));
+    if line_starts.len() > 1 && line_starts.last() == Some(&original.len()) {
+        line_starts.pop();
+    }
+    let line_of = |byte: usize| line_starts.partition_point(|&start| start <= byte) - 1;
+    let line_end = |line: usize| line_starts.get(line + 1).copied().unwrap_or(original.len());
+    let line_text = |line: usize| &original[line_starts[line]..line_end(line)];
+
+    // The lines each edit changes, merged where edits change the same lines.
+    let mut blocks: Vec<(usize, usize, Vec<&Edit>)> = Vec::new();
+    for edit in applied {
+        let first = line_of(edit.range.start);
+        let last = line_of(edit.range.end.max(edit.range.start + 1) - 1).max(first);
+        match blocks.last_mut() {
+            Some((_, block_last, block)) if first <= *block_last => {
+                *block_last = (*block_last).max(last);
+                block.push(edit);
+            }
+            _ => blocks.push((first, last, vec![edit])),
+        }
+    }
+
+    let mut diff = String::new();
+    // How many more lines there are in the changed file before the current hunk.
+    let mut offset = 0isize;
+    let mut blocks = blocks.into_iter().peekable();
+    while let Some(block) = blocks.next() {
+        // The blocks close enough to share their context are in the same hunk.
+        let mut hunk = vec![block];
+        while let Some(next) = blocks.next_if(|next| {
+            next.0 <= hunk.last().expect("not empty").1 + 2 * CONTEXT + 1
+        }) {
+            hunk.push(next);
+        }
+        let first = hunk[0].0.saturating_sub(CONTEXT);
+        let last = (hunk.last().expect("not empty").1 + CONTEXT).min(line_starts.len() - 1);
+        let mut lines = String::new();
+        let (mut removed, mut added, mut context) = (0, 0, 0);
+        let mut line = first;
+        for (block_first, block_last, edits) in &hunk {
+            for context_line in line..*block_first {
+                push_line(&mut lines, ' ', line_text(context_line));
+                context += 1;
+            }
+            let start = line_starts[*block_first];
+            let old = &original[start..line_end(*block_last)];
+            let mut new = String::with_capacity(old.len());
+            let mut copied = start;
+            for edit in edits {
+                new.push_str(&original[copied..edit.range.start]);
+                new.push_str(&edit.replacement);
+                copied = edit.range.end;
+            }
+            new.push_str(&original[copied..line_end(*block_last)]);
+            for old_line in old.split_inclusive('\n') {
+                push_line(&mut lines, '-', old_line);
+                removed += 1;
+            }
+            for new_line in new.split_inclusive('\n') {
+                push_line(&mut lines, '+', new_line);
+                added += 1;
+            }
+            line = block_last + 1;
+        }
+        for context_line in line..=last {
+            push_line(&mut lines, ' ', line_text(context_line));
+            context += 1;
+        }
+        if diff.is_empty() {
+            diff.push_str(&format!("--- a/{0}\n+++ b/{0}\n", path.display()));
+        }
+        let old_start = first + 1;
+        let new_start = (old_start as isize + offset) as usize;
+        diff.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(old_start, context + removed),
+            hunk_range(new_start, context + added),
+        ));
+        diff.push_str(&lines);
+        offset += added as isize - removed as isize;
+    }
+    diff
+}
+
+/// The `START,COUNT` of a hunk. Hunks with no lines start at the line before them.
+fn hunk_range(start: usize, count: usize) -> String {
+    if count == 0 {
+        format!("{},0", start - 1)
+    } else {
+        format!("{},{}", start, count)
+    }
+}
+
+/// Add the `line` to a hunk, marking a missing newline at the end of the file like `diff` does.
+fn push_line(hunk: &mut String, marker: char, line: &str) {
+    hunk.push(marker);
+    hunk.push_str(line);
+    if !line.ends_with('\n') {
+        hunk.push_str("\n\\ No newline at end of file\n");
+    }
+}
+
+/// The changes according to git, with paths relative to the `repo_root`.
+///
+/// These are the changes since `base`, in the commits up to `HEAD` when it is given and in the
+/// working tree otherwise. Files git does not track yet count as changed either way.
+pub(crate) async fn git_changes(
+    repo_root: &Path,
+    base: Option<&str>,
+) -> Result<ChangedLines, Error> {
+    let range = match base {
+        Some(base) => format!("{}...HEAD", base),
+        None => String::from("HEAD"),
+    };
+    // Renamed files are followed, so that their lints are matched against the new path.
+    let diff = git(repo_root, "diff", &[
+        "--unified=0", "--no-color", "--no-ext-diff", "--find-renames", "--relative", &range,
+    ]).await?;
+    let mut changed = ChangedLines::parse(&diff);
+    let untracked = git(repo_root, "ls-files", &["--others", "--exclude-standard"]).await?;
+    changed.new_files.extend(untracked.lines().map(PathBuf::from));
+    Ok(changed)
+}
+
+async fn git(repo_root: &Path, subcommand: &'static str, args: &[&str]) -> Result<String, Error> {
+    let output = tokio::process::Command::new("git")
+        .arg(subcommand)
+        .args(args)
+        .current_dir(repo_root)
+        .stderr(std::process::Stdio::inherit())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(Error::Run)?;
+    if !output.status.success() {
+        return Err(Error::ExitStatus(subcommand, output.status));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collects_added_lines() {
+        let diff = "\
+diff --git a/src/lib.rs b/src/lib.rs
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -3,2 +3,3 @@ fn one() {
+ unchanged
+-removed
++added
++++ counter;
+@@ -20 +21,0 @@
+-removed
+diff --git a/old.rs b/new.rs
+--- a/old.rs
++++ b/new.rs
+@@ -1 +1 @@
+-old
++new
+--- a/deleted.rs
++++ /dev/null
+@@ -1 +0,0 @@
+-gone
+";
+        let changed = ChangedLines::parse(diff);
+        let lib = Path::new("src/lib.rs");
+        assert!(!changed.contains(lib, 3));
+        assert!(changed.contains(lib, 4));
+        assert!(changed.contains(lib, 5));
+        assert!(!changed.contains(lib, 6));
+        assert!(!changed.contains(lib, 21));
+        assert!(changed.contains(Path::new("new.rs"), 1));
+        assert!(!changed.contains(Path::new("old.rs"), 1));
+        assert!(!changed.contains(Path::new("deleted.rs"), 1));
+    }
+
+    #[test]
+    fn applies_edits_as_hunks() {
+        let original = "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine\nten\nend";
+        let edit = |text: &str, replacement: &str| {
+            let start = original.find(text).unwrap();
+            Edit { range: start..start + text.len(), replacement: replacement.into() }
+        };
+        let edits = [
+            edit("end", "fin"),
+            edit("two", "2"),
+            // Overlaps the edit of `two`.
+            edit("wo\nthr", "x"),
+            edit("wo", "oo"),
+            edit("one\n", ""),
+        ];
+        assert_eq!(unified(Path::new("src/lib.rs"), original, &edits), "\
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -1,5 +1,4 @@
+-one
+-two
++2
+ three
+ four
+ five
+@@ -8,4 +7,4 @@
+ eight
+ nine
+ ten
+-end
+\\ No newline at end of file
++fin
+\\ No newline at end of file
+");
+        assert_eq!(unified(Path::new("src/lib.rs"), original, &[]), "");
+    }
+}
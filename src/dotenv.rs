@@ -0,0 +1,136 @@
+use std::path::{Path, PathBuf};
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum Error {
+    #[error("could not read the environment file {1:?}")]
+    Read(#[source] std::io::Error, PathBuf),
+    #[error("{0:?}:{1}: expected `KEY=VALUE`")]
+    Malformed(PathBuf, usize),
+    #[error("{0:?}:{1}: `{2}` is not a valid variable name")]
+    InvalidName(PathBuf, usize, String),
+    #[error("{0:?}:{1}: unterminated quoted value")]
+    UnterminatedQuote(PathBuf, usize),
+}
+
+impl crate::ClassifyError for Error {
+    fn kind(&self) -> crate::ErrorKind {
+        crate::ErrorKind::User
+    }
+}
+
+/// Whether `name` can be the name of an environment variable in a shell.
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Load `KEY=VALUE` pairs from a dotenv-style file.
+///
+/// Blank lines, lines starting with `#` and an optional `export ` prefix are ignored. Values may
+/// be wrapped in single quotes (taken literally) or double quotes (supporting `\n`, `\t`, `\"`
+/// and `\\` escapes). Unquoted values are trimmed and may be followed by a ` #` comment.
+pub(crate) fn load(path: &Path) -> Result<Vec<(String, String)>, Error> {
+    let contents = std::fs::read_to_string(path).map_err(|e| Error::Read(e, path.into()))?;
+    parse(&contents, path)
+}
+
+/// Parse the `contents` of the file at `path`, which is only used in the errors.
+fn parse(contents: &str, path: &Path) -> Result<Vec<(String, String)>, Error> {
+    let mut vars = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (name, value) = match line.find('=') {
+            Some(idx) => (line[..idx].trim(), line[idx + 1..].trim()),
+            None => return Err(Error::Malformed(path.into(), line_number)),
+        };
+        if !is_valid_name(name) {
+            return Err(Error::InvalidName(path.into(), line_number, name.into()));
+        }
+        let value = parse_value(value)
+            .ok_or_else(|| Error::UnterminatedQuote(path.into(), line_number))?;
+        vars.push((String::from(name), value));
+    }
+    Ok(vars)
+}
+
+fn parse_value(value: &str) -> Option<String> {
+    if let Some(rest) = value.strip_prefix('\'') {
+        return rest.find('\'').map(|end| String::from(&rest[..end]));
+    }
+    if let Some(rest) = value.strip_prefix('"') {
+        let mut result = String::with_capacity(rest.len());
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => return Some(result),
+                '\\' => match chars.next()? {
+                    'n' => result.push('\n'),
+                    't' => result.push('\t'),
+                    other => result.push(other),
+                },
+                c => result.push(c),
+            }
+        }
+        return None;
+    }
+    let value = match value.find(" #") {
+        Some(idx) => &value[..idx],
+        None => value,
+    };
+    Some(String::from(value.trim_end()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Error};
+    use std::path::Path;
+
+    fn vars(contents: &str) -> Vec<(String, String)> {
+        parse(contents, Path::new(".env")).unwrap()
+    }
+
+    #[test]
+    fn parses_quoted_values() {
+        assert_eq!(vars(r#"
+SINGLE='a \n "b" # c'
+DOUBLE="a\n\tb \"c\" \\ # d"
+EMPTY=""
+"#), [
+            (String::from("SINGLE"), String::from(r#"a \n "b" # c"#)),
+            (String::from("DOUBLE"), String::from("a\n\tb \"c\" \\ # d")),
+            (String::from("EMPTY"), String::new()),
+        ]);
+    }
+
+    #[test]
+    fn strips_comments_and_export() {
+        assert_eq!(vars("
+# a comment
+export  ONE = 1 # the first
+TWO=a#b
+  export THREE=3
+"), [
+            (String::from("ONE"), String::from("1")),
+            (String::from("TWO"), String::from("a#b")),
+            (String::from("THREE"), String::from("3")),
+        ]);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        let path = Path::new(".env");
+        assert!(matches!(parse("A=1\nB\n", path), Err(Error::Malformed(_, 2))));
+        assert!(matches!(
+            parse("1A=1", path),
+            Err(Error::InvalidName(_, 1, name)) if name == "1A"
+        ));
+        assert!(matches!(parse("\nA=\"1", path), Err(Error::UnterminatedQuote(_, 2))));
+        assert!(matches!(parse("A='1", path), Err(Error::UnterminatedQuote(_, 1))));
+    }
+}
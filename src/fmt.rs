@@ -1,8 +1,7 @@
 use tokio::process::Command;
-use futures::StreamExt;
 use std::fmt::Write;
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
-use crate::jsonl::FilterReportedExt;
 
 #[derive(thiserror::Error, Debug)]
 pub(crate) enum Error {
@@ -12,6 +11,42 @@ pub(crate) enum Error {
     CommandOutput(#[source] crate::jsonl::Error),
     #[error("formatting issues found")]
     Formatting,
+    #[error("could not query `cargo fmt` for supported options")]
+    QueryHelp(#[source] std::io::Error),
+    #[error("neither `cargo fmt` supports `--message-format json` nor `rustfmt` supports \
+             `--emit json`, rustfmt 1.4.6 or newer is required (or pass --fmt-fallback to only \
+             check the exit code)")]
+    MessageFormatUnsupported,
+    #[error("could not run `cargo fmt -- --check`")]
+    RunFallback(#[source] std::io::Error),
+    #[error("--fmt-files pattern {1:?} is not valid")]
+    FilesPattern(#[source] glob::PatternError, String),
+    #[error("could not expand --fmt-files pattern")]
+    FilesGlob(#[source] glob::GlobError),
+    #[error("could not find the workspace targets to format")]
+    Metadata(#[source] crate::metadata::Error),
+    #[error(transparent)]
+    Deadline(#[from] crate::DeadlineError),
+    #[error(transparent)]
+    Interrupted(#[from] crate::InterruptedError),
+}
+
+impl crate::ClassifyError for Error {
+    fn kind(&self) -> crate::ErrorKind {
+        match self {
+            Error::PublishLints(e) => e.kind(),
+            Error::CommandOutput(e) => e.kind(),
+            Error::Formatting => crate::ErrorKind::User,
+            Error::QueryHelp(_) => crate::ErrorKind::User,
+            Error::MessageFormatUnsupported => crate::ErrorKind::User,
+            Error::RunFallback(_) => crate::ErrorKind::User,
+            Error::FilesPattern(..) => crate::ErrorKind::User,
+            Error::FilesGlob(_) => crate::ErrorKind::User,
+            Error::Metadata(e) => e.kind(),
+            Error::Deadline(e) => e.kind(),
+            Error::Interrupted(e) => e.kind(),
+        }
+    }
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -55,41 +90,324 @@ fn make_lint(file: &Path, mismatch: &MismatchSchema) -> Result<crate::phab::Lint
     })
 }
 
+/// Produce an autofix lint, which carries the exact replacement for the mismatched lines.
+///
+/// Harbormaster lints have no field for the replacement text, so it is included in the
+/// description instead.
+fn make_autofix_lint(file: &Path, mismatch: &MismatchSchema) -> crate::phab::Lint {
+    let description = format!(
+        "Replace lines {}-{} with:\n```lang=rust\n{}\n```",
+        mismatch.original_begin_line, mismatch.original_end_line, mismatch.expected
+    );
+    crate::phab::Lint {
+        name: "format mismatch".into(),
+        code: "RUSTFMT".into(),
+        severity: crate::phab::Severity::Autofix,
+        path: PathBuf::from(file).into(),
+        description: Some(description.into()),
+        line: Some(mismatch.original_begin_line),
+        column: None,
+    }
+}
+
+/// The path with the symbolic links resolved, to compare the paths rustfmt reports with, or the
+/// path itself if it cannot be resolved.
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Parse the mismatches rustfmt reports.
+///
+/// Depending on the version, rustfmt prints an array of files per line, or a single array that
+/// spans many lines. Both are a sequence of JSON values. Should anything else be printed as well,
+/// the lines that are not JSON are returned separately.
+fn parse_output(stdout: &[u8]) -> (Vec<FileSchema>, Vec<String>) {
+    let values = serde_json::Deserializer::from_slice(stdout).into_iter::<Vec<FileSchema>>();
+    if let Ok(files) = values.collect::<Result<Vec<_>, _>>() {
+        return (files.into_iter().flatten().collect(), Vec::new());
+    }
+    let mut files = Vec::new();
+    let mut unparsed = Vec::new();
+    for line in stdout.split(|&b| b == b'\n') {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        match serde_json::from_slice::<Vec<FileSchema>>(line) {
+            Ok(line_files) => files.extend(line_files),
+            Err(_) => unparsed.push(String::from_utf8_lossy(line).into_owned()),
+        }
+    }
+    (files, unparsed)
+}
+
 impl crate::Context {
+    /// A `rustfmt` command that reports the mismatches as JSON instead of formatting the files.
+    fn rustfmt_command(&self, args: &clap::ArgMatches<'_>) -> Command {
+        let mut cmd = Command::new("rustfmt");
+        cmd.arg("--emit").arg("json")
+            .envs(self.env())
+            .kill_on_drop(true);
+        if let Some(args) = args.values_of_os("args") {
+            cmd.args(args);
+        }
+        cmd
+    }
+
+    /// `--emit json` is unstable in some rustfmt versions, and missing in older ones, so check
+    /// that it is accepted by formatting an empty input with it.
+    async fn rustfmt_supports_json(&self) -> bool {
+        Command::new("rustfmt")
+            .arg("--emit").arg("json")
+            .envs(self.env())
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .status()
+            .await
+            .map(|status| status.success())
+            .unwrap_or(false)
+    }
+
     pub(crate) async fn fmt(&self, args: &clap::ArgMatches<'_>) -> Result<(), Error> {
+        let mut only_files = None;
+        let commands = if let Some(patterns) = args.values_of("fmt_files") {
+            let files = self.fmt_files(patterns)?;
+            if files.is_empty() {
+                self.warn("--fmt-files did not match any files");
+                return Ok(());
+            }
+            // `cargo fmt` always formats all of the targets, even if files are specified, so
+            // invoke rustfmt directly, with the edition of the package each file belongs to.
+            let commands = self.rustfmt_files_commands(args, &files).await?;
+            only_files = Some(files.iter().map(|f| canonical(f)).collect());
+            commands
+        } else if self.fmt_supports_message_format().await? {
+            let mut cmd = self.cargo_json_command("fmt");
+            if let Some(args) = args.values_of_os("args") {
+                cmd.args(args);
+            }
+            vec![cmd]
+        } else if self.rustfmt_supports_json().await {
+            self.warn("`cargo fmt` does not support `--message-format json`, running rustfmt on \
+                       the workspace targets directly");
+            self.rustfmt_target_commands(args).await?
+        } else if args.is_present("fmt_fallback") {
+            self.warn("`cargo fmt` does not support `--message-format json`, only the exit code \
+                       of `cargo fmt -- --check` will be reported");
+            return self.fmt_fallback(args).await;
+        } else {
+            return Err(Error::MessageFormatUnsupported);
+        };
         let mut lints = Vec::with_capacity(64);
-        let result = self.fmt_inner(&mut lints, args).await;
+        let result = self.with_heartbeat(
+            self.fmt_inner(&mut lints, commands, only_files.as_ref(), args)
+        ).await;
         if !lints.is_empty() {
-            self.publish_work(
+            let published = self.publish_work(
+                None,
                 &lints,
                 &[],
             ).await.map_err(Error::PublishLints)?;
+            self.report_published(&published);
+            match result {
+                Err(e @ Error::Deadline(_)) | Err(e @ Error::Interrupted(_)) => return Err(e),
+                _ => {}
+            }
             return Err(Error::Formatting);
         }
         result
     }
 
-    pub(crate) async fn fmt_inner(&self, lints: &mut Vec<crate::phab::Lint>, args: &clap::ArgMatches<'_>) -> Result<(), Error> {
-        let mut cmd = Command::new("cargo");
-        cmd.arg("fmt")
-            .arg("--message-format").arg("json")
-            .kill_on_drop(true);
-        if let Some(args) = args.values_of_os("args") {
-            cmd.args(args);
+    pub(crate) async fn fmt_inner(
+        &self,
+        lints: &mut Vec<crate::phab::Lint>,
+        commands: Vec<Command>,
+        only_files: Option<&HashSet<PathBuf>>,
+        args: &clap::ArgMatches<'_>,
+    ) -> Result<(), Error> {
+        for cmd in commands {
+            self.fmt_command(lints, cmd, only_files, args).await?;
         }
-        let mut values = self.get_stdout_json_lines(cmd).filter_reported();
-        while let Some(result) = values.next().await {
-            let files: Vec<FileSchema> = result.map_err(Error::CommandOutput)?;
-            for file in files {
-                for mismatch in &file.mismatches {
-                    let filename = Path::new(&file.name);
-                    let filename = filename.strip_prefix(&self.arcconfig).unwrap_or(filename);
-                    let lint = make_lint(filename, mismatch)?;
-                    lint.report();
-                    lints.push(lint);
+        Ok(())
+    }
+
+    async fn fmt_command(
+        &self,
+        lints: &mut Vec<crate::phab::Lint>,
+        mut cmd: Command,
+        only_files: Option<&HashSet<PathBuf>>,
+        args: &clap::ArgMatches<'_>,
+    ) -> Result<(), Error> {
+        cmd.stdout(std::process::Stdio::piped());
+        let output = match cmd.output().await {
+            Ok(output) => output,
+            Err(e) => return Err(Error::CommandOutput(crate::jsonl::Error::Spawn(e, Box::new(cmd)))),
+        };
+        let (files, unparsed) = parse_output(&output.stdout);
+        for line in unparsed {
+            self.warn(format_args!("rustfmt output a line that couldn't be parsed:\n{}", line));
+        }
+        for file in files {
+            if file.mismatches.is_empty() {
+                continue;
+            }
+            // rustfmt also formats the modules the specified files declare, which the patterns
+            // may not have matched.
+            if only_files.is_some_and(|only| !only.contains(&canonical(Path::new(&file.name)))) {
+                continue;
+            }
+            // Phabricator would not know what to do with a path outside of the repository
+            // (such as a file generated in the target directory).
+            let path = match self.try_repo_relative(Path::new(&file.name)) {
+                Some(path) => path,
+                None => {
+                    self.warn(format_args!(
+                        "skipping formatting issues in {}, it is outside of the repository",
+                        file.name
+                    ));
+                    continue;
+                }
+            };
+            for mismatch in &file.mismatches {
+                let lint = if args.is_present("fmt_autofix") {
+                    make_autofix_lint(&path, mismatch)
+                } else {
+                    make_lint(&path, mismatch)?
+                };
+                if !self.reports(&lint) {
+                    continue;
+                }
+                self.report_lint(&lint);
+                lints.push(lint);
+            }
+        }
+        if !output.status.success() {
+            return Err(Error::CommandOutput(crate::jsonl::Error::ExitStatus(output.status)));
+        }
+        Ok(())
+    }
+
+    /// A rustfmt invocation for each of the workspace targets, equivalent to what `cargo fmt`
+    /// would run.
+    async fn rustfmt_target_commands(&self, args: &clap::ArgMatches<'_>) -> Result<Vec<Command>, Error> {
+        let metadata = self.metadata().await.map_err(Error::Metadata)?;
+        let mut seen = std::collections::HashSet::new();
+        let mut commands = Vec::new();
+        for package in metadata.workspace_packages() {
+            for target in &package.targets {
+                if !seen.insert(&target.src_path) {
+                    continue;
                 }
+                let mut cmd = self.rustfmt_command(args);
+                cmd.arg("--edition").arg(&target.edition).arg(&target.src_path);
+                commands.push(cmd);
+            }
+        }
+        Ok(commands)
+    }
+
+    /// A rustfmt invocation for the files of each edition. Files outside of the workspace packages
+    /// are formatted with the default edition of rustfmt.
+    async fn rustfmt_files_commands(&self, args: &clap::ArgMatches<'_>, files: &[PathBuf])
+    -> Result<Vec<Command>, Error> {
+        let metadata = self.metadata().await.map_err(Error::Metadata)?;
+        let mut editions = std::collections::BTreeMap::<_, Vec<_>>::new();
+        for file in files {
+            let edition = metadata.workspace_package_of(file).map(|p| &p.edition);
+            editions.entry(edition).or_default().push(file);
+        }
+        Ok(editions.into_iter().map(|(edition, files)| {
+            let mut cmd = self.rustfmt_command(args);
+            if let Some(edition) = edition {
+                cmd.arg("--edition").arg(edition);
+            }
+            cmd.args(files);
+            cmd
+        }).collect())
+    }
+
+    /// Expand the `--fmt-files` patterns relative to the repository root.
+    fn fmt_files<'a>(&self, patterns: impl Iterator<Item=&'a str>) -> Result<Vec<PathBuf>, Error> {
+        let mut files = Vec::new();
+        for pattern in patterns {
+            let full_pattern = self.repo_root.join(pattern);
+            let full_pattern = full_pattern.to_string_lossy();
+            let paths = glob::glob(&full_pattern)
+                .map_err(|e| Error::FilesPattern(e, String::from(pattern)))?;
+            for path in paths {
+                let path = path.map_err(Error::FilesGlob)?;
+                if path.is_file() && !files.contains(&path) {
+                    files.push(path);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Older rustfmt versions do not know about `--message-format` and fail when it is
+    /// specified, so look for it in the help output before relying on it.
+    async fn fmt_supports_message_format(&self) -> Result<bool, Error> {
+        let output = self.cargo_command("fmt")
+            .arg("--help")
+            .output()
+            .await
+            .map_err(Error::QueryHelp)?;
+        Ok(String::from_utf8_lossy(&output.stdout).contains("--message-format"))
+    }
+
+    async fn fmt_fallback(&self, args: &clap::ArgMatches<'_>) -> Result<(), Error> {
+        let mut cmd = self.cargo_command("fmt");
+        let mut has_separator = false;
+        if let Some(args) = args.values_of_os("args") {
+            for arg in args {
+                has_separator |= arg == "--";
+                cmd.arg(arg);
             }
         }
+        if !has_separator {
+            cmd.arg("--");
+        }
+        cmd.arg("--check");
+        let status = cmd.status().await.map_err(Error::RunFallback)?;
+        if !status.success() {
+            return Err(Error::Formatting);
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_output;
+
+    fn names(stdout: &str) -> (Vec<String>, Vec<String>) {
+        let (files, unparsed) = parse_output(stdout.as_bytes());
+        (files.into_iter().map(|f| f.name).collect(), unparsed)
+    }
+
+    #[test]
+    fn parses_array_per_line() {
+        let (names, unparsed) = names(include_str!("../tests/fixtures/rustfmt-lines.json"));
+        assert_eq!(names, ["/repo/src/lib.rs", "/repo/src/main.rs"]);
+        assert!(unparsed.is_empty());
+    }
+
+    #[test]
+    fn parses_single_document() {
+        let (names, unparsed) = names(include_str!("../tests/fixtures/rustfmt-document.json"));
+        assert_eq!(names, ["/repo/src/lib.rs", "/repo/src/main.rs"]);
+        assert!(unparsed.is_empty());
+    }
+
+    #[test]
+    fn keeps_lines_around_garbage() {
+        let stdout = format!(
+            "Warning: can't set `imports_granularity`\n{}",
+            include_str!("../tests/fixtures/rustfmt-lines.json")
+        );
+        let (names, unparsed) = names(&stdout);
+        assert_eq!(names, ["/repo/src/lib.rs", "/repo/src/main.rs"]);
+        assert_eq!(unparsed, ["Warning: can't set `imports_granularity`"]);
+    }
+}
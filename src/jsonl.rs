@@ -12,20 +12,30 @@ pub(crate) enum StreamValuesError {
 #[derive(thiserror::Error, Debug)]
 pub(crate) enum Error {
     #[error("could not spawn command: {1:?}")]
-    Spawn(#[source] std::io::Error, tokio::process::Command),
+    Spawn(#[source] std::io::Error, Box<tokio::process::Command>),
     #[error("could not obtain the exit code")]
     WaitChild(#[source] std::io::Error),
     #[error("command failed with {0}")]
     ExitStatus(std::process::ExitStatus),
     #[error(transparent)]
     StreamValue(#[from] StreamValuesError),
-    #[error("could not parse a value as json")]
-    ParseValue(#[source] serde_json::Error),
+}
+
+impl crate::ClassifyError for Error {
+    fn kind(&self) -> crate::ErrorKind {
+        match self {
+            // Most commonly a missing `cargo` or a command failing due to e.g. compile errors.
+            Error::Spawn(..) => crate::ErrorKind::User,
+            Error::ExitStatus(_) => crate::ErrorKind::User,
+            Error::WaitChild(_) => crate::ErrorKind::Internal,
+            Error::StreamValue(_) => crate::ErrorKind::Internal,
+        }
+    }
 }
 
 pub(crate) trait FilterReportedExt<'a> {
     type Filtered;
-    fn filter_reported(self) -> Self::Filtered;
+    fn filter_reported(self, log: crate::phab::Log) -> Self::Filtered;
 }
 
 impl<'a, S, T> FilterReportedExt<'a> for S
@@ -33,25 +43,18 @@ where S: futures::Stream<Item=Result<T, Error>> + Send + 'a,
       T: 'a + Send
 {
     type Filtered = futures::stream::BoxStream<'a, Result<T, Error>>;
-    fn filter_reported(self) -> Self::Filtered {
-        self.filter_map(|v| async move {
+    fn filter_reported(self, log: crate::phab::Log) -> Self::Filtered {
+        self.filter_map(move |v| async move {
             match v {
                 Ok(v) => Some(Ok(v)),
                 Err(crate::jsonl::Error::StreamValue(
                         crate::jsonl::StreamValuesError::ParseLine(e, line)
                 )) => {
-                    eprintln!(
-                        "warning: `cargo` output a value that couldn't be parsed: {}\n{}",
+                    log.warn(format_args!(
+                        "`cargo` output a value that couldn't be parsed: {}\n{}",
                         e,
                         String::from_utf8_lossy(&line)
-                    );
-                    return None;
-                },
-                Err(crate::jsonl::Error::ParseValue(e)) => {
-                    eprintln!(
-                        "warning: `cargo` output a value that couldn't be parsed: {}",
-                        e,
-                    );
+                    ));
                     return None;
                 },
                 Err(e) => return Some(Err(e)),
@@ -61,34 +64,51 @@ where S: futures::Stream<Item=Result<T, Error>> + Send + 'a,
 }
 
 impl crate::Context {
-    pub(crate) fn get_reason_json_lines<T>(&self, cmd: tokio::process::Command, reason: &'static str)
+    pub(crate) fn get_stdout_json_lines<T>(&self, cmd: tokio::process::Command)
     -> impl futures::Stream<Item=Result<T, Error>>
     where T: serde::de::DeserializeOwned + Send + 'static {
-        self.get_stdout_json_lines(cmd).filter_map(move |v| {
-            async move {
-                let value: serde_json::Value = match v {
-                    Err(e) => return Some(Err(e)),
-                    Ok(v) => v,
-                };
-                if value.get("reason").and_then(|v| v.as_str()) != Some(reason) {
-                    return None;
-                }
-                match serde_json::from_value(value) {
-                    Err(e) => return Some(Err(Error::ParseValue(e))),
-                    Ok(v) => return Some(Ok(v)),
-                }
-            }
-        })
+        self.spawn_json_lines(cmd).0
+    }
 
+    /// Like `get_stdout_json_lines`, but also collecting the lines the command outputs to stderr,
+    /// after passing them on to our own stderr.
+    ///
+    /// The future completes with the lines once the command closes its stderr, and has to be
+    /// polled along with the stream for the command not to get stuck.
+    pub(crate) fn get_stdout_json_lines_and_stderr<T>(&self, mut cmd: tokio::process::Command)
+    -> (impl futures::Stream<Item=Result<T, Error>>, impl futures::Future<Output=Vec<String>>)
+    where T: serde::de::DeserializeOwned + Send + 'static {
+        cmd.stderr(std::process::Stdio::piped());
+        let (values, stderr) = self.spawn_json_lines(cmd);
+        let lines = async move {
+            let mut lines = Vec::new();
+            let mut reader = match stderr {
+                Some(stderr) => tokio::io::BufReader::new(stderr),
+                None => return lines,
+            };
+            let mut line = Vec::new();
+            while reader.read_until(b'\n', &mut line).await.is_ok_and(|n| n > 0) {
+                let text = String::from_utf8_lossy(&line);
+                eprint!("{}", text);
+                lines.push(String::from(text.trim_end()));
+                line.clear();
+            }
+            lines
+        };
+        (values, lines)
     }
 
-    pub(crate) fn get_stdout_json_lines<T>(&self, mut cmd: tokio::process::Command)
-    -> impl futures::Stream<Item=Result<T, Error>>
+    fn spawn_json_lines<T>(&self, mut cmd: tokio::process::Command)
+    -> (
+        futures::stream::BoxStream<'static, Result<T, Error>>,
+        Option<tokio::process::ChildStderr>,
+    )
     where T: serde::de::DeserializeOwned + Send + 'static {
         cmd.stdout(std::process::Stdio::piped());
         match cmd.spawn() {
             Ok(mut c) => {
-                self.stream_values(
+                let stderr = c.stderr.take();
+                let values = self.stream_values(
                     tokio::io::BufReader::new(c.stdout.take().expect("we're capturing the stdout"))
                 ).map_err(Error::StreamValue).chain(async move {
                     let exit_status = c.wait_with_output().await.map_err(Error::WaitChild)?.status;
@@ -96,9 +116,10 @@ impl crate::Context {
                         return Err(Error::ExitStatus(exit_status));
                     }
                     Ok(None)
-                }.into_stream().filter_map(|v| async move { v.transpose() })).boxed()
+                }.into_stream().filter_map(|v| async move { v.transpose() })).boxed();
+                (values, stderr)
             }
-            Err(e) => async move { Err(Error::Spawn(e, cmd)) }.into_stream().boxed(),
+            Err(e) => (async move { Err(Error::Spawn(e, Box::new(cmd))) }.into_stream().boxed(), None),
         }
     }
 
@@ -108,16 +129,24 @@ impl crate::Context {
           R: AsyncBufReadExt + Unpin,
     {
         let line_buffer = Vec::with_capacity(1024);
-        futures::stream::try_unfold((reader, line_buffer), |(mut reader, mut line)| {
+        // A line that can't be parsed does not end the stream, so that the values after it are
+        // still read, and the command does not get stuck writing into a pipe nobody reads.
+        futures::stream::unfold(Some((reader, line_buffer)), |state| {
             async move {
+                let (mut reader, mut line) = state?;
                 line.clear();
-                reader.read_until(b'\n', &mut line).await.map_err(StreamValuesError::ReadLine)?;
+                if let Err(e) = reader.read_until(b'\n', &mut line).await {
+                    return Some((Err(StreamValuesError::ReadLine(e)), None));
+                }
                 if line.is_empty() {
-                    return Ok(None);
+                    return None;
                 }
                 match serde_json::from_slice(&line) {
-                    Ok(v) => return Ok(Some((v, (reader, line)))),
-                    Err(e) => return Err(StreamValuesError::ParseLine(e, line)),
+                    Ok(v) => Some((Ok(v), Some((reader, line)))),
+                    Err(e) => {
+                        let value = Err(StreamValuesError::ParseLine(e, line.clone()));
+                        Some((value, Some((reader, line))))
+                    }
                 }
             }
         })
@@ -0,0 +1,99 @@
+use std::path::PathBuf;
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum Error {
+    #[error("could not read the JUnit report {1:?}")]
+    Read(#[source] std::io::Error, PathBuf),
+    #[error("could not parse the JUnit report {1:?}")]
+    Parse(#[source] roxmltree::Error, PathBuf),
+}
+
+impl crate::ClassifyError for Error {
+    fn kind(&self) -> crate::ErrorKind {
+        crate::ErrorKind::User
+    }
+}
+
+/// The message attribute and the text of an element such as `<failure>`.
+fn describe(node: roxmltree::Node<'_, '_>) -> Option<String> {
+    let message = node.attribute("message").unwrap_or("").trim();
+    let text = node.text().unwrap_or("").trim();
+    match (message.is_empty(), text.is_empty()) {
+        (true, true) => None,
+        (false, true) => Some(String::from(message)),
+        (true, false) => Some(String::from(text)),
+        (false, false) => Some(format!("{}\n\n{}", message, text)),
+    }
+}
+
+/// Convert the `<testcase>`s of a JUnit XML report to test results.
+///
+/// The `classname` is used as the namespace. Reports differ in whether the cases are inside of a
+/// `<testsuites>` element, so they are looked for anywhere in the document.
+pub(crate) fn parse(xml: &str) -> Result<Vec<crate::phab::Test>, roxmltree::Error> {
+    let document = roxmltree::Document::parse(xml)?;
+    let mut tests = Vec::new();
+    for case in document.descendants().filter(|n| n.has_tag_name("testcase")) {
+        let outcome = case.children().find_map(|child| {
+            let result = match child.tag_name().name() {
+                "failure" => crate::phab::TestResult::Fail,
+                "error" => crate::phab::TestResult::Broken,
+                "skipped" => crate::phab::TestResult::Skip,
+                _ => return None,
+            };
+            Some((result, describe(child)))
+        });
+        let (result, details) = outcome.unwrap_or((crate::phab::TestResult::Pass, None));
+        tests.push(crate::phab::Test {
+            name: String::from(case.attribute("name").unwrap_or("")).into(),
+            result,
+            namespace: case.attribute("classname")
+                .filter(|c| !c.is_empty())
+                .map(|c| String::from(c).into()),
+            duration: case.attribute("time").and_then(|t| t.parse().ok()),
+            format: details.as_ref().map(|_| "text".into()),
+            details: details.map(Into::into),
+        });
+    }
+    Ok(tests)
+}
+
+/// Load and parse the JUnit XML report at `path`.
+pub(crate) fn load(path: &std::path::Path) -> Result<Vec<crate::phab::Test>, Error> {
+    let xml = std::fs::read_to_string(path).map_err(|e| Error::Read(e, path.into()))?;
+    parse(&xml).map_err(|e| Error::Parse(e, path.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::phab::TestResult;
+
+    #[test]
+    fn maps_testcases() {
+        let tests = super::parse(r#"<?xml version="1.0" encoding="UTF-8"?>
+            <testsuites>
+              <testsuite name="demo">
+                <testcase name="passes" classname="demo::tests" time="0.5"/>
+                <testcase name="fails" classname="demo::tests">
+                  <failure message="assertion failed">left: 1, right: 2</failure>
+                </testcase>
+                <testcase name="crashes"><error/></testcase>
+                <testcase name="ignored"><skipped message="needs network"/></testcase>
+              </testsuite>
+            </testsuites>"#).unwrap();
+        let summary: Vec<_> = tests.iter()
+            .map(|t| (&t.name[..], t.namespace.as_deref(), t.details.as_deref()))
+            .collect();
+        assert_eq!(summary, [
+            ("passes", Some("demo::tests"), None),
+            ("fails", Some("demo::tests"), Some("assertion failed\n\nleft: 1, right: 2")),
+            ("crashes", None, None),
+            ("ignored", None, Some("needs network")),
+        ]);
+        assert!(matches!(tests[0].result, TestResult::Pass));
+        assert!(matches!(tests[1].result, TestResult::Fail));
+        assert!(matches!(tests[2].result, TestResult::Broken));
+        assert!(matches!(tests[3].result, TestResult::Skip));
+        assert_eq!(tests[0].duration, Some(0.5));
+    }
+}
@@ -4,45 +4,741 @@ mod check;
 mod fmt;
 mod test;
 mod jsonl;
+mod dotenv;
+mod metadata;
+mod junit;
+mod diff;
+mod paths;
+mod report;
+mod cargo_config;
 
 /// Context containing data typically shared between the subcommands.
+#[cfg_attr(test, derive(Default))]
 struct Context {
-    phab_uri: String,
+    /// The address the conduit methods are under, `PHABRICATOR_URI/api` unless given otherwise.
+    conduit_uri: String,
     build_phid: String,
     token: String,
-    arcconfig: std::path::PathBuf,
+    /// The directory paths are reported relative to, by default the one with the `.arcconfig`.
+    repo_root: std::path::PathBuf,
+    /// The cargo to run, the one running us when invoked as `cargo phabricator`.
+    cargo: std::ffi::OsString,
+    manifest_path: Option<std::path::PathBuf>,
+    offline: bool,
+    locked: bool,
+    /// `--frozen`, which is `--offline` and `--locked` together.
+    frozen: bool,
+    /// The `--target` to build for, if not the host.
+    target: Option<String>,
+    heartbeat: Option<std::time::Duration>,
+    /// When the work has to stop, from `--deadline`, along with its number of seconds.
+    deadline: Option<(tokio::time::Instant, u64)>,
+    client: reqwest::Client,
+    batch_size: usize,
+    dry_run: bool,
+    no_publish: bool,
+    conduit_retries: u32,
+    output_format: phab::OutputFormat,
+    verbosity: Verbosity,
+    severity_map: std::collections::HashMap<check::LintLevel, phab::Severity>,
+    fail_on: Option<phab::Severity>,
+    /// Where the results are published, from `--report-to`.
+    reporters: Vec<Box<dyn report::Reporter>>,
+    /// The file each of the `harbormaster.sendmessage` payloads are appended to.
+    save_payload: Option<std::path::PathBuf>,
+    /// Lint codes not to publish, from `--allow`.
+    allow_codes: Vec<String>,
+    /// The only lint codes to publish, from `--only`, if any are given.
+    only_codes: Vec<String>,
+    /// Only lints in files matching these are reported, if any are given.
+    include_paths: Vec<glob::Pattern>,
+    /// Lints in files matching these are not reported, even if they match `include_paths`.
+    exclude_paths: Vec<glob::Pattern>,
+    /// The lines changed by the diff under review, with `--changed-only`.
+    changed_lines: Option<diff::ChangedLines>,
+    /// Whether lints without a line are reported with `--changed-only`.
+    keep_lineless: bool,
+    /// Whether to leave the rendered diagnostic out of the lint descriptions.
+    no_rendered: bool,
+    /// Whether to link the documentation of the lints from their descriptions.
+    lint_links: bool,
+    /// Variables from `--env`, set for all of the commands run.
+    env: Vec<(String, String)>,
+    /// Lints to deny through the rustflags.
+    deny: Vec<String>,
+    /// The `target.KEY` tables of the configuration that set `rustflags`, with `--deny`.
+    target_rustflags: Vec<String>,
+    /// The lints and tests collected so far.
+    summary: std::sync::Mutex<phab::Summary>,
+    /// `cargo metadata`, queried when first needed.
+    metadata: std::sync::OnceLock<metadata::MetadataSchema>,
+}
+
+/// How the `--deny` lints are passed to cargo.
+#[derive(Debug, PartialEq)]
+enum DenyFlags {
+    /// Extending the flags in the environment variable.
+    Env(&'static str, std::ffi::OsString),
+    /// With a `--config` for `build.rustflags` and each of the `target.KEY.rustflags`, which add to
+    /// the flags from the configuration.
+    Config(Vec<String>),
+}
+
+/// Cargo only uses the first of `CARGO_ENCODED_RUSTFLAGS`, `RUSTFLAGS` and the configuration, so
+/// the lints are added to whichever of these is in use, according to `var_os`.
+///
+/// Within the configuration, the `target` tables take precedence over `build.rustflags`, so the
+/// lints are added to the `target_keys` that set `rustflags` as well.
+fn deny_rustflags(
+    deny: &[String],
+    target_keys: &[String],
+    var_os: impl Fn(&str) -> Option<std::ffi::OsString>,
+) -> Option<DenyFlags> {
+    if deny.is_empty() {
+        return None;
+    }
+    let (name, separator, mut flags) = match var_os("CARGO_ENCODED_RUSTFLAGS") {
+        Some(flags) => ("CARGO_ENCODED_RUSTFLAGS", "\x1f", flags),
+        None => match var_os("RUSTFLAGS") {
+            Some(flags) => ("RUSTFLAGS", " ", flags),
+            None => {
+                let flags = deny.iter()
+                    .flat_map(|lint| vec![String::from("-D"), lint.clone()])
+                    .collect::<Vec<_>>();
+                let flags = serde_json::to_string(&flags).expect("strings serialize");
+                let mut configs = vec![format!("build.rustflags={}", flags)];
+                for key in target_keys {
+                    let key = serde_json::to_string(key).expect("strings serialize");
+                    configs.push(format!("target.{}.rustflags={}", key, flags));
+                }
+                return Some(DenyFlags::Config(configs));
+            }
+        },
+    };
+    for lint in deny {
+        if !flags.is_empty() {
+            flags.push(separator);
+        }
+        flags.push("-D");
+        flags.push(separator);
+        flags.push(lint);
+    }
+    Some(DenyFlags::Env(name, flags))
+}
+
+impl Context {
+    /// A `cargo SUBCOMMAND` command, with the options that apply to all of the cargo invocations.
+    ///
+    /// The `subcommand` may consist of multiple words, like `nextest run`.
+    pub(crate) fn cargo_command(&self, subcommand: &str) -> tokio::process::Command {
+        let mut cmd = tokio::process::Command::new(&self.cargo);
+        cmd.args(subcommand.split(' ')).kill_on_drop(true).envs(self.env());
+        if let Some(manifest_path) = &self.manifest_path {
+            cmd.arg("--manifest-path").arg(manifest_path);
+        }
+        // `cargo fmt` needs neither the network nor the lock file, and does not accept these
+        // flags either.
+        if subcommand != "fmt" {
+            if self.frozen {
+                cmd.arg("--frozen");
+            } else {
+                if self.offline {
+                    cmd.arg("--offline");
+                }
+                if self.locked {
+                    cmd.arg("--locked");
+                }
+            }
+        }
+        // Neither formatting nor the metadata depend on the target.
+        if let Some(target) = &self.target {
+            if !matches!(subcommand, "fmt" | "metadata") {
+                cmd.arg("--target").arg(target);
+            }
+        }
+        // Nor does formatting depend on the lints.
+        if subcommand != "fmt" {
+            match deny_rustflags(&self.deny, &self.target_rustflags, |name| self.var_os(name)) {
+                Some(DenyFlags::Env(name, flags)) => { cmd.env(name, flags); }
+                Some(DenyFlags::Config(configs)) => {
+                    for config in configs {
+                        cmd.arg("--config").arg(config);
+                    }
+                }
+                None => {}
+            }
+        }
+        cmd
+    }
+
+    /// The `--env` variables, to set on a command.
+    pub(crate) fn env(&self) -> impl Iterator<Item=(&String, &String)> {
+        self.env.iter().map(|(k, v)| (k, v))
+    }
+
+    /// The variable as the commands that are run see it, with `--env` taking precedence.
+    pub(crate) fn var_os(&self, name: &str) -> Option<std::ffi::OsString> {
+        match self.env.iter().rev().find(|(k, _)| k == name) {
+            Some((_, value)) => Some(value.into()),
+            None => std::env::var_os(name),
+        }
+    }
+
+    /// A `cargo SUBCOMMAND` command that outputs its messages as JSON.
+    pub(crate) fn cargo_json_command(&self, subcommand: &str) -> tokio::process::Command {
+        let mut cmd = self.cargo_command(subcommand);
+        cmd.arg("--message-format").arg("json");
+        cmd
+    }
+
+    /// Whether the lint is reported, according to `--allow`, `--only`, `--include-path`,
+    /// `--exclude-path` and `--changed-only`.
+    fn reports(&self, lint: &phab::Lint) -> bool {
+        self.reports_code(&lint.code) && self.reports_path(&lint.path) && self.reports_line(lint)
+    }
+
+    /// Whether the line of the lint was changed, if only changed lines are reported.
+    fn reports_line(&self, lint: &phab::Lint) -> bool {
+        match (&self.changed_lines, lint.line) {
+            (None, _) => true,
+            (Some(changed), Some(line)) => changed.contains(&lint.path, line),
+            (Some(_), None) => self.keep_lineless,
+        }
+    }
+
+    /// Whether lints in the file at the repository relative `path` are reported.
+    fn reports_path(&self, path: &std::path::Path) -> bool {
+        if self.exclude_paths.iter().any(|p| p.matches_path(path)) {
+            return false;
+        }
+        self.include_paths.is_empty() || self.include_paths.iter().any(|p| p.matches_path(path))
+    }
+
+    /// Whether lints with `code` are reported, according to `--allow` and `--only`.
+    fn reports_code(&self, code: &str) -> bool {
+        // `CODE` matches just that code, `PREFIX*` all the codes starting with `PREFIX`.
+        let matches = |pattern: &String| match pattern.strip_suffix('*') {
+            Some(prefix) => code.starts_with(prefix),
+            None => code == pattern,
+        };
+        if self.allow_codes.iter().any(matches) {
+            return false;
+        }
+        self.only_codes.is_empty() || self.only_codes.iter().any(matches)
+    }
+
+    /// The path relative to the repository root, as Phabricator expects it in lints.
+    ///
+    /// Paths outside of the repository are returned as they are.
+    fn repo_relative(&self, path: &std::path::Path) -> std::path::PathBuf {
+        let path = self.workspace_path(path);
+        self.try_repo_relative(&path).unwrap_or_else(|| {
+            path.canonicalize().map(paths::simplified).unwrap_or_else(|_| path.into())
+        })
+    }
+
+    /// Cargo reports paths relative to the workspace root, which is not necessarily the current
+    /// directory, e.g. with `--manifest-path`. The root is known once the metadata is queried.
+    fn workspace_path<'a>(&self, path: &'a std::path::Path) -> std::borrow::Cow<'a, std::path::Path> {
+        match self.metadata.get() {
+            Some(metadata) if path.is_relative() => metadata.workspace_root.join(path).into(),
+            _ => path.into(),
+        }
+    }
+
+    /// Like `repo_relative`, but `None` for files outside of the repository.
+    ///
+    /// Paths reached through a symlink inside the repository count as inside, as do paths into
+    /// a repository that is itself reached through a symlink.
+    fn try_repo_relative(&self, path: &std::path::Path) -> Option<std::path::PathBuf> {
+        let path = self.workspace_path(path);
+        if let Some(relative) = paths::strip_root(&path, &self.repo_root) {
+            return Some(relative);
+        }
+        let path = path.canonicalize().ok()?;
+        let root = self.repo_root.canonicalize().unwrap_or_else(|_| self.repo_root.clone());
+        paths::strip_root(&path, &root)
+    }
+}
+
+/// How much is printed besides what is published to Phabricator.
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug, Default)]
+pub(crate) enum Verbosity {
+    Quiet,
+    #[default]
+    Normal,
+}
+
+/// Whether an error is something the user can correct, or indicates a bug in this tool.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum ErrorKind {
+    User,
+    Internal,
+}
+
+pub(crate) trait ClassifyError {
+    fn kind(&self) -> ErrorKind;
+}
+
+/// The error returned from the top-level of the program, remembering its classification.
+struct MainError {
+    kind: ErrorKind,
+    error: Box<dyn std::error::Error>,
+}
+
+impl<E: std::error::Error + ClassifyError + 'static> From<E> for MainError {
+    fn from(error: E) -> Self {
+        MainError {
+            kind: error.kind(),
+            error: Box::new(error),
+        }
+    }
+}
+
+impl ClassifyError for std::io::Error {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Internal
+    }
 }
 
 fn subcommand_args<'a, 'b>(sc: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
     sc.arg(clap::Arg::with_name("args").raw(true))
 }
 
+fn path_patterns(matches: &clap::ArgMatches, name: &str) -> Vec<glob::Pattern> {
+    matches.values_of(name).into_iter().flatten()
+        .map(|v| glob::Pattern::new(v).expect("validated by clap"))
+        .collect()
+}
+
+/// The package and feature selection flags of the subcommands that build the packages.
+fn feature_args<'a, 'b>(sc: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    sc
+        .arg(
+            clap::Arg::with_name("workspace")
+                .long("workspace")
+                .help("Build all of the packages in the workspace")
+        )
+        .arg(
+            clap::Arg::with_name("package")
+                .long("package")
+                .short("p")
+                .help("Only build this package, as with cargo")
+                .value_name("SPEC")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(
+            clap::Arg::with_name("exclude")
+                .long("exclude")
+                .help("Leave this package out of the --workspace")
+                .value_name("SPEC")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(
+            clap::Arg::with_name("features")
+                .long("features")
+                .help("Features of the packages to enable, as with cargo")
+                .value_name("FEATURES")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(
+            clap::Arg::with_name("all_features")
+                .long("all-features")
+                .help("Enable all of the features of the packages")
+        )
+        .arg(
+            clap::Arg::with_name("no_default_features")
+                .long("no-default-features")
+                .help("Do not enable the default features of the packages")
+        )
+}
+
+/// The cargo arguments for the `feature_args`, to pass before those after the `--`.
+pub(crate) fn cargo_feature_args<'a>(args: &'a clap::ArgMatches<'_>) -> Vec<&'a str> {
+    let mut cargo_args = Vec::new();
+    // Cargo refuses `--workspace` given twice, and it may be among the arguments after the `--`.
+    let raw_workspace = args.values_of_os("args").into_iter().flatten()
+        .take_while(|arg| *arg != "--")
+        .any(|arg| arg == "--workspace" || arg == "--all");
+    if args.is_present("workspace") && !raw_workspace {
+        cargo_args.push("--workspace");
+    }
+    for package in args.values_of("package").into_iter().flatten() {
+        cargo_args.push("--package");
+        cargo_args.push(package);
+    }
+    for package in args.values_of("exclude").into_iter().flatten() {
+        cargo_args.push("--exclude");
+        cargo_args.push(package);
+    }
+    for features in args.values_of("features").into_iter().flatten() {
+        cargo_args.push("--features");
+        cargo_args.push(features);
+    }
+    if args.is_present("all_features") {
+        cargo_args.push("--all-features");
+    }
+    if args.is_present("no_default_features") {
+        cargo_args.push("--no-default-features");
+    }
+    cargo_args
+}
+
+/// The options shared by the subcommands that run libtest harnesses.
+fn harness_args<'a, 'b>(sc: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    sc
+        .arg(
+            clap::Arg::with_name("only_report_failures")
+                .long("only-report-failures")
+                .help("Only publish tests that did not pass")
+        )
+        .arg(
+            clap::Arg::with_name("test_env_file")
+                .long("test-env-file")
+                .help("Load environment variables for the test binaries from a `KEY=VALUE` file")
+                .takes_value(true)
+        )
+        .arg(
+            clap::Arg::with_name("test_output_limit")
+                .long("test-output-limit")
+                .help("Maximum number of bytes of output to publish for each failing test")
+                .value_name("BYTES")
+                .takes_value(true)
+                .default_value("65536")
+                .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+        )
+        .arg(
+            clap::Arg::with_name("test_backtrace")
+                .long("test-backtrace")
+                .help("The RUST_BACKTRACE to run the test binaries with, so that the backtraces \
+                    of panics are published with the failures. Defaults to 1, unless \
+                    RUST_BACKTRACE is set already")
+                .value_name("VALUE")
+                .takes_value(true)
+                .possible_values(&["0", "1", "full"])
+        )
+        .arg(
+            clap::Arg::with_name("test_timeout")
+                .long("test-timeout")
+                .help("Kill test binaries that have not finished after SECONDS, reporting them \
+                    as broken")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .validator(|v| match v.parse::<u64>() {
+                    Ok(0) | Err(_) => Err(String::from("must be a positive number of seconds")),
+                    Ok(_) => Ok(()),
+                })
+        )
+}
+
 #[derive(thiserror::Error, Debug)]
-#[error("could not find the .arcconfig")]
+#[error("could not load the .arcconfig")]
 struct FindArcConfigError(#[source] crate::arcconfig::Error);
 
 #[derive(thiserror::Error, Debug)]
-#[error("phabricator.uri not specified in .arcconfig nor is --phabricator-uri")]
-struct GetLocationError;
+#[error("could not load the ~/.arcrc")]
+struct LoadArcRcError(#[source] crate::arcconfig::Error);
+
+#[derive(thiserror::Error, Debug)]
+#[error("could not run {1:?}, make sure cargo is in PATH or set CARGO to its location")]
+struct FindCargoError(#[source] std::io::Error, std::ffi::OsString);
+
+#[derive(thiserror::Error, Debug)]
+#[error("could not create the --save-payload file {1:?}")]
+struct CreatePayloadFileError(#[source] std::io::Error, std::path::PathBuf);
+
+#[derive(thiserror::Error, Debug)]
+#[error("could not create the --report-to file {1:?}")]
+struct CreateReportFileError(#[source] std::io::Error, std::path::PathBuf);
+
+#[derive(thiserror::Error, Debug)]
+#[error("could not find the --repo-root {1:?}")]
+struct FindRepoRootError(#[source] std::io::Error, std::path::PathBuf);
+
+#[derive(thiserror::Error, Debug)]
+#[error("could not find the --manifest-path {1:?}")]
+struct FindManifestError(#[source] std::io::Error, std::path::PathBuf);
+
+#[derive(thiserror::Error, Debug)]
+#[error("--manifest-path {0:?} is not a Cargo.toml")]
+struct NotAManifestError(std::path::PathBuf);
+
+#[derive(thiserror::Error, Debug)]
+#[error("could not read the --cacert {1:?}")]
+struct ReadCaCertError(#[source] std::io::Error, std::path::PathBuf);
+
+#[derive(thiserror::Error, Debug)]
+#[error("could not parse the --cacert {1:?} as a PEM certificate")]
+struct ParseCaCertError(#[source] reqwest::Error, std::path::PathBuf);
+
+#[derive(thiserror::Error, Debug)]
+#[error("phabricator.uri not specified in .arcconfig or ~/.arcrc nor is --phabricator-uri, \
+         --conduit-uri or {0}")]
+struct GetLocationError(String);
+
+#[derive(thiserror::Error, Debug)]
+#[error("--build-phid not available, nor is {0} or any of the --build-phid-from-harbormaster-env \
+         variables")]
+struct GetBuildPhidError(String);
 
 #[derive(thiserror::Error, Debug)]
-#[error("--build-phid not available")]
-struct GetBuildPhidError;
+#[error("--conduit-token not available, nor is {0}, --conduit-token-file or a token for this host \
+         in ~/.arcrc")]
+struct GetConduitTokenError(String);
 
 #[derive(thiserror::Error, Debug)]
-#[error("--conduit-token not available")]
-struct GetConduitTokenError;
+#[error("could not read the --conduit-token-file {1:?}")]
+struct ReadConduitTokenError(#[source] std::io::Error, std::path::PathBuf);
+
+#[derive(thiserror::Error, Debug)]
+#[error("the --conduit-token-file {0:?} is empty")]
+struct EmptyConduitTokenError(std::path::PathBuf);
+
+#[derive(thiserror::Error, Debug)]
+#[error("both --conduit-token-file and {0} are set, only one should be")]
+struct ConduitTokenSourcesError(String);
 
 #[derive(thiserror::Error, Debug)]
 #[error("subcommand is not implemented")]
 struct UnimplementedSubcommand;
 
+#[derive(thiserror::Error, Debug)]
+#[error("the --deadline of {0} seconds passed, only the results until then were published")]
+pub(crate) struct DeadlineError(u64);
+
+#[derive(thiserror::Error, Debug)]
+#[error("interrupted by {0}, only the results until then were published")]
+pub(crate) struct InterruptedError(&'static str);
+
+#[derive(thiserror::Error, Debug)]
+#[error("could not set up the HTTP client")]
+struct BuildClientError(#[source] reqwest::Error);
+
+impl ClassifyError for BuildClientError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Internal
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+struct PreflightError(&'static str, #[source] crate::phab::Error);
+
+impl ClassifyError for PreflightError {
+    fn kind(&self) -> ErrorKind {
+        self.1.kind()
+    }
+}
+
+macro_rules! user_errors {
+    ($($ty: ty),*) => {$(
+        impl ClassifyError for $ty {
+            fn kind(&self) -> ErrorKind {
+                ErrorKind::User
+            }
+        }
+    )*}
+}
+
+user_errors!(
+    FindArcConfigError,
+    FindCargoError,
+    FindManifestError,
+    FindRepoRootError,
+    CreatePayloadFileError,
+    CreateReportFileError,
+    NotAManifestError,
+    LoadArcRcError,
+    ReadCaCertError,
+    ParseCaCertError,
+    GetLocationError,
+    GetBuildPhidError,
+    GetConduitTokenError,
+    ReadConduitTokenError,
+    EmptyConduitTokenError,
+    ConduitTokenSourcesError,
+    UnimplementedSubcommand,
+    DeadlineError,
+    InterruptedError
+);
+
+/// Obtain the value of `arg`, or read it from the environment variable named by `env_arg`.
+fn value_or_env(matches: &clap::ArgMatches, arg: &str, env_arg: &str) -> Option<String> {
+    matches.value_of(arg)
+        .map(String::from)
+        .or_else(|| std::env::var(env_name(matches, env_arg)).ok())
+}
+
+fn env_name(matches: &clap::ArgMatches, env_arg: &str) -> String {
+    String::from(matches.value_of(env_arg).expect("has a default value"))
+}
+
+/// The --build-phid for the `kind` of results, or the one given for all of them.
+fn build_phid_for(matches: &clap::ArgMatches, kind: &str) -> Option<String> {
+    let mut fallback = None;
+    for value in matches.values_of("build_phid")? {
+        match value.split_once('=') {
+            Some((k, phid)) if k == kind => return Some(String::from(phid)),
+            Some(_) => {}
+            None => fallback = fallback.or_else(|| Some(String::from(value))),
+        }
+    }
+    fallback
+}
+
+/// Read the API token from --conduit-token-file, if it is given.
+fn conduit_token_file(matches: &clap::ArgMatches) -> Result<Option<String>, MainError> {
+    let path = match matches.value_of_os("conduit_token_file") {
+        Some(path) => std::path::PathBuf::from(path),
+        None => return Ok(None),
+    };
+    let env_name = env_name(matches, "conduit_token_env");
+    if std::env::var_os(&env_name).is_some() {
+        return Err(ConduitTokenSourcesError(env_name).into());
+    }
+    let token = std::fs::read_to_string(&path).map_err(|e| ReadConduitTokenError(e, path.clone()))?;
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(EmptyConduitTokenError(path).into());
+    }
+    Ok(Some(String::from(token)))
+}
+
+/// Look for the build target PHID in the environment variables Harbormaster integrations are
+/// known to set.
+fn harbormaster_build_phid(matches: &clap::ArgMatches) -> Option<String> {
+    matches.values_of("harbormaster_env")?
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.is_empty())
+}
+
+/// Lets the Phabricator administrators tell the requests of this tool apart from those of `arc`.
+const DEFAULT_USER_AGENT: &str = concat!(clap::crate_name!(), "/", clap::crate_version!());
+
+/// Parse a `Name: Value` header of `--header`.
+fn header(header: &str)
+-> Result<(reqwest::header::HeaderName, reqwest::header::HeaderValue), String> {
+    let (name, value) = header.split_once(':')
+        .ok_or_else(|| format!("expected `Name: Value`, got `{}`", header))?;
+    let name = reqwest::header::HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("`{}` is not a valid header name", name.trim()))?;
+    let value = reqwest::header::HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("the value of the `{}` header is not valid", name))?;
+    Ok((name, value))
+}
+
+/// Parse a `KEY=VALUE` pair of `--env`.
+fn env_var(var: &str) -> Result<(String, String), String> {
+    let (name, value) = var.split_once('=')
+        .ok_or_else(|| format!("expected `KEY=VALUE`, got `{}`", var))?;
+    if !dotenv::is_valid_name(name) {
+        return Err(format!("`{}` is not a valid variable name", name));
+    }
+    Ok((name.into(), value.into()))
+}
+
+/// Parse a `LEVEL=SEVERITY` pair of `--severity-map`.
+fn severity_mapping(mapping: &str) -> Result<(check::LintLevel, phab::Severity), String> {
+    let (level, severity) = mapping.split_once('=')
+        .ok_or_else(|| format!("expected `LEVEL=SEVERITY`, got `{}`", mapping))?;
+    Ok((level.trim().parse()?, severity.trim().parse()?))
+}
+
 fn main() {
-    let fmt_subcommand = subcommand_args(clap::SubCommand::with_name("fmt"));
-    let check_subcommand = subcommand_args(clap::SubCommand::with_name("check"));
-    let build_subcommand = subcommand_args(clap::SubCommand::with_name("build"));
-    let test_subcommand = subcommand_args(clap::SubCommand::with_name("test"));
-    let clippy_subcommand = subcommand_args(clap::SubCommand::with_name("clippy"));
+    let fmt_subcommand = subcommand_args(clap::SubCommand::with_name("fmt"))
+        .arg(
+            clap::Arg::with_name("fmt_fallback")
+                .long("fmt-fallback")
+                .help("Fall back to `cargo fmt -- --check` if rustfmt is too old to produce JSON. \
+                    Only the exit code is reported in this mode")
+        )
+        .arg(
+            clap::Arg::with_name("fmt_files")
+                .long("fmt-files")
+                .help("Only check formatting of files matching this glob, relative to the \
+                    repository root. The arguments after `--` are passed to rustfmt directly \
+                    in this mode")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(
+            clap::Arg::with_name("fmt_autofix")
+                .long("autofix")
+                .help("Report formatting mismatches as autofix lints with the formatted code, \
+                    rather than as errors with a diff")
+        );
+    let check_subcommand = feature_args(subcommand_args(clap::SubCommand::with_name("check")));
+    let build_subcommand = feature_args(subcommand_args(clap::SubCommand::with_name("build")))
+        .arg(
+            clap::Arg::with_name("upload_artifacts")
+                .long("upload-artifacts")
+                .help("Upload the built executables to Phabricator as build artifacts")
+        );
+    let test_subcommand = feature_args(harness_args(subcommand_args(
+        clap::SubCommand::with_name("test")
+    )))
+        .after_help("The arguments after `--` are passed to `cargo test`. Those after a second \
+            `--` are passed to the test binaries, e.g. `cargo phabricator test -- --release -- \
+            --test-threads=1 FILTER`.")
+        .arg(
+            clap::Arg::with_name("test_junit")
+                .long("junit")
+                .help("Publish the results from a JUnit XML report, instead of building and \
+                    running the tests")
+                .value_name("FILE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(
+            clap::Arg::with_name("test_runner")
+                .long("runner")
+                .help("Run the tests with the builtin libtest harness, or with `cargo nextest`")
+                .takes_value(true)
+                .possible_values(&["builtin", "nextest"])
+                .default_value("builtin")
+        )
+        .arg(
+            clap::Arg::with_name("no_run_tests")
+                .long("no-run-tests")
+                .help("Only build the tests and publish the diagnostics from compiling them, for \
+                    targets the tests cannot run on")
+                .conflicts_with("test_junit")
+        )
+        .arg(
+            clap::Arg::with_name("test_jobs")
+                .long("test-jobs")
+                .help("How many test binaries to run at once. Defaults to CARGO_BUILD_JOBS or the \
+                    number of CPUs")
+                .value_name("N")
+                .takes_value(true)
+                .validator(|v| match v.parse::<usize>() {
+                    Ok(0) | Err(_) => Err(String::from("must be a positive number")),
+                    Ok(_) => Ok(()),
+                })
+        );
+    let bench_subcommand = feature_args(harness_args(subcommand_args(
+        clap::SubCommand::with_name("bench")
+    )))
+        .after_help("The arguments after `--` are passed to `cargo bench`. Those after a second \
+            `--` are passed to the benchmark binaries, e.g. `cargo phabricator bench -- --release \
+            -- FILTER`. The benchmarks are run one binary at a time.");
+    let clippy_subcommand = feature_args(subcommand_args(clap::SubCommand::with_name("clippy")))
+        .arg(
+            clap::Arg::with_name("clippy_fixes_artifact")
+                .long("clippy-fixes-artifact")
+                .help("Upload the machine applicable suggestions as a patch to Phabricator, as \
+                    the \"Suggested clippy fixes\" build artifact")
+        );
+    let reset_subcommand = clap::SubCommand::with_name("reset")
+        .about("Send an empty `work` message to the build target, before publishing new results");
 
     let cli = clap::App::new(clap::crate_name!())
         .version(clap::crate_version!())
@@ -63,73 +759,756 @@ fn main() {
                     `  .arcconfig` may be used for defaults")
                 .takes_value(true)
                 .required(false)
-                .env("PHABRICATOR_URI")
+        )
+        .arg(
+            clap::Arg::with_name("conduit_uri")
+                .long("conduit-uri")
+                .help("Address of the conduit API, if it is not at the /api of the \
+                    --phabricator-uri")
+                .value_name("URI")
+                .takes_value(true)
+                .validator(|v| reqwest::Url::parse(&v).map(|_| ()).map_err(|e| e.to_string()))
+        )
+        .arg(
+            clap::Arg::with_name("phabricator_uri_env")
+                .long("phabricator-uri-env")
+                .help("Environment variable to read the Phabricator address from")
+                .takes_value(true)
+                .default_value("PHABRICATOR_URI")
         )
         .arg(
             clap::Arg::with_name("conduit_token")
                 .long("conduit-token")
                 .help("API token to use when contacting Phabricator")
-                .required(true)
                 .takes_value(true)
-                .env("CONDUIT_TOKEN")
+        )
+        .arg(
+            clap::Arg::with_name("conduit_token_env")
+                .long("conduit-token-env")
+                .help("Environment variable to read the API token from")
+                .takes_value(true)
+                .default_value("CONDUIT_TOKEN")
+        )
+        .arg(
+            clap::Arg::with_name("conduit_token_file")
+                .long("conduit-token-file")
+                .help("Read the API token from a file, which keeps it out of the process list \
+                    and the environment")
+                .value_name("PATH")
+                .takes_value(true)
+                .conflicts_with("conduit_token")
         )
         .arg(
             clap::Arg::with_name("build_phid")
                 .long("build-phid")
-                .help("The PHID of the Harbormaster build that should receive results")
-                .required(true)
+                .help("The PHID of the Harbormaster build that should receive results. Use \
+                    `lint=PHID` and `test=PHID` to send lints and test results to different \
+                    build targets")
+                .value_name("[TYPE=]PHID")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|v| match v.split_once('=') {
+                    None | Some(("lint", _)) | Some(("test", _)) => Ok(()),
+                    Some((kind, _)) => Err(format!("`{}` is not one of `lint` or `test`", kind)),
+                })
+        )
+        .arg(
+            clap::Arg::with_name("build_phid_env")
+                .long("build-phid-env")
+                .help("Environment variable to read the build PHID from")
                 .takes_value(true)
-                .env("BUILD_PHID")
+                .default_value("BUILD_PHID")
+        )
+        .arg(
+            clap::Arg::with_name("harbormaster_env")
+                .long("build-phid-from-harbormaster-env")
+                .help("Environment variables to look for the build PHID in, if --build-phid is \
+                    not specified")
+                .takes_value(true)
+                .use_delimiter(true)
+                .default_value("HARBORMASTER_BUILD_TARGET_PHID,TARGET_PHID,PHID")
+        )
+        .arg(
+            clap::Arg::with_name("manifest_path")
+                .long("manifest-path")
+                .help("Path to the Cargo.toml to pass to cargo. The .arcconfig is looked for \
+                    starting from its directory")
+                .value_name("PATH")
+                .takes_value(true)
+        )
+        .arg(
+            clap::Arg::with_name("allow")
+                .long("allow")
+                .help("Do not report lints with CODE, e.g. `CHECKunused_variables`. A trailing \
+                    `*` matches all the codes starting with what comes before it, e.g. `CLIPPY*`")
+                .value_name("CODE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(
+            clap::Arg::with_name("only")
+                .long("only")
+                .help("Only report lints with CODE, matched like --allow")
+                .value_name("CODE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(
+            clap::Arg::with_name("include_path")
+                .long("include-path")
+                .help("Only report lints in files matching GLOB, relative to the repository root")
+                .value_name("GLOB")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|v| glob::Pattern::new(&v).map(|_| ()).map_err(|e| e.to_string()))
+        )
+        .arg(
+            clap::Arg::with_name("exclude_path")
+                .long("exclude-path")
+                .help("Do not report lints in files matching GLOB, relative to the repository \
+                    root, even if they match --include-path")
+                .value_name("GLOB")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|v| glob::Pattern::new(&v).map(|_| ()).map_err(|e| e.to_string()))
+        )
+        .arg(
+            clap::Arg::with_name("changed_only")
+                .long("changed-only")
+                .help("Only report lints on the lines changed by the diff under review, by \
+                    default the uncommitted changes according to `git diff`")
+        )
+        .arg(
+            clap::Arg::with_name("base_ref")
+                .long("base-ref")
+                .help("With --changed-only, take the changes from the commits since REF instead")
+                .value_name("REF")
+                .takes_value(true)
+                .requires("changed_only")
+                .conflicts_with("diff")
+        )
+        .arg(
+            clap::Arg::with_name("diff")
+                .long("diff")
+                .help("Read the changes for --changed-only from a unified diff in FILE")
+                .value_name("FILE")
+                .takes_value(true)
+                .requires("changed_only")
+        )
+        .arg(
+            clap::Arg::with_name("drop_lineless")
+                .long("drop-lineless")
+                .help("With --changed-only, do not report lints that are not on any line either")
+                .requires("changed_only")
+        )
+        .arg(
+            clap::Arg::with_name("no_rendered")
+                .long("no-rendered")
+                .help("Leave the diagnostic as rendered by the compiler out of the lint \
+                    descriptions, for smaller uploads")
+        )
+        .arg(
+            clap::Arg::with_name("no_lint_links")
+                .long("no-lint-links")
+                .help("Do not link the documentation of the rustc and clippy lints from their \
+                    descriptions")
+        )
+        .arg(
+            clap::Arg::with_name("env")
+                .long("env")
+                .help("Set the environment variable KEY for cargo, rustfmt and the test binaries. \
+                    Takes precedence over the inherited environment and --test-env-file")
+                .value_name("KEY=VALUE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|v| env_var(&v).map(|_| ()))
+        )
+        .arg(
+            clap::Arg::with_name("deny")
+                .long("deny")
+                .help("Deny LINT, like `-D LINT` in RUSTFLAGS would, so that it is reported as an \
+                    error. Adds to the RUSTFLAGS or CARGO_ENCODED_RUSTFLAGS in the environment, or \
+                    to build.rustflags and the target.<triple>.rustflags and \
+                    target.'cfg(..)'.rustflags of the cargo configuration. The \
+                    CARGO_TARGET_<TRIPLE>_RUSTFLAGS variables are only added to for the --target. \
+                    Changing the flags makes cargo rebuild everything")
+                .value_name("LINT")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(
+            clap::Arg::with_name("repo_root")
+                .long("repo-root")
+                .help("Report paths relative to this directory rather than the one containing \
+                    the .arcconfig")
+                .value_name("PATH")
+                .takes_value(true)
+        )
+        .arg(
+            clap::Arg::with_name("offline")
+                .long("offline")
+                .help("Pass --offline to cargo, so that it does not access the network")
+        )
+        .arg(
+            clap::Arg::with_name("locked")
+                .long("locked")
+                .help("Pass --locked to cargo, so that it fails rather than update Cargo.lock")
+        )
+        .arg(
+            clap::Arg::with_name("frozen")
+                .long("frozen")
+                .help("Pass --frozen to cargo, which implies both --offline and --locked")
+        )
+        .arg(
+            clap::Arg::with_name("target")
+                .long("target")
+                .help("Build for the target TRIPLE. The tests are run with the \
+                    CARGO_TARGET_<TRIPLE>_RUNNER, or skipped if it is not set and the target is \
+                    not the host")
+                .value_name("TRIPLE")
+                .takes_value(true)
+        )
+        .arg(
+            clap::Arg::with_name("heartbeat")
+                .long("heartbeat")
+                .help("Let Harbormaster know the build is in progress as soon as a subcommand \
+                    starts and every SECONDS after that")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .validator(|v| match v.parse::<u64>() {
+                    Ok(0) | Err(_) => Err(String::from("must be a positive number of seconds")),
+                    Ok(_) => Ok(()),
+                })
+        )
+        .arg(
+            clap::Arg::with_name("deadline")
+                .long("deadline")
+                .help("Stop the work SECONDS after starting and publish the results collected \
+                    until then, to finish before the time limit of the CI job")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .validator(|v| match v.parse::<u64>() {
+                    Ok(0) | Err(_) => Err(String::from("must be a positive number of seconds")),
+                    Ok(_) => Ok(()),
+                })
+        )
+        .arg(
+            clap::Arg::with_name("batch_size")
+                .long("batch-size")
+                .help("Maximum number of lints or tests to send to Phabricator in one request")
+                .takes_value(true)
+                .default_value("100")
+                .validator(|v| match v.parse::<usize>() {
+                    Ok(0) | Err(_) => Err(String::from("must be a positive number")),
+                    Ok(_) => Ok(()),
+                })
+        )
+        .arg(
+            clap::Arg::with_name("dry_run")
+                .long("dry-run")
+                .help("Print what would be published instead of sending it to Phabricator. \
+                    --build-phid and --conduit-token are not required in this mode")
+        )
+        .arg(
+            clap::Arg::with_name("save_payload")
+                .long("save-payload")
+                .help("Save the payload of each message sent to Harbormaster to PATH, a line of \
+                    JSON each, without the API token")
+                .value_name("PATH")
+                .takes_value(true)
+        )
+        .arg(
+            clap::Arg::with_name("report_to")
+                .long("report-to")
+                .help("Where to publish the results: to Harbormaster with `conduit`, the default, \
+                    or as a line of JSON for each message to `stdout` or to the file at PATH. Can \
+                    be given several times")
+                .value_name("conduit|stdout|PATH")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(
+            clap::Arg::with_name("no_publish")
+                .long("no-publish")
+                .conflicts_with("dry_run")
+                .help("Only report the results to the terminal, without publishing anything. \
+                    Useful to run the same checks locally, e.g. from a pre-commit hook")
+        )
+        .arg(
+            clap::Arg::with_name("quiet")
+                .long("quiet")
+                .short("q")
+                .help("Do not print the lints and tests found, only publish them")
+        )
+        .arg(
+            clap::Arg::with_name("output_format")
+                .long("output-format")
+                .help("How to print the lints and tests found. `json` prints a JSON object for \
+                    each of them per line")
+                .takes_value(true)
+                .possible_values(&["human", "json"])
+                .default_value("human")
+        )
+        .arg(
+            clap::Arg::with_name("severity_map")
+                .long("severity-map")
+                .help("Report rustc lint levels with a different Phabricator severity, e.g. \
+                    `warning=error,help=advice`")
+                .value_name("LEVEL=SEVERITY")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .validator(|v| severity_mapping(&v).map(|_| ()))
+        )
+        .arg(
+            clap::Arg::with_name("fail_on")
+                .long("fail-on")
+                .help("Exit with an error if any lints with this severity or higher were found. \
+                    Formatting mismatches always fail `fmt`")
+                .takes_value(true)
+                .possible_values(&["advice", "warning", "error"])
+        )
+        .arg(
+            clap::Arg::with_name("conduit_timeout")
+                .long("conduit-timeout")
+                .help("How long to wait for Phabricator to respond to a request")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .default_value("30")
+                .validator(|v| match v.parse::<u64>() {
+                    Ok(0) | Err(_) => Err(String::from("must be a positive number of seconds")),
+                    Ok(_) => Ok(()),
+                })
+        )
+        .arg(
+            clap::Arg::with_name("proxy")
+                .long("proxy")
+                .help("Send conduit requests through this proxy, instead of the ones specified by \
+                    the HTTP_PROXY and HTTPS_PROXY environment variables")
+                .value_name("URL")
+                .takes_value(true)
+                .validator(|v| reqwest::Proxy::all(&v).map(|_| ()).map_err(|e| e.to_string()))
+        )
+        .arg(
+            clap::Arg::with_name("cacert")
+                .long("cacert")
+                .help("Trust the PEM certificate at PATH when connecting to Phabricator, e.g. \
+                    the root of an internal certificate authority")
+                .value_name("PATH")
+                .takes_value(true)
+        )
+        .arg(
+            clap::Arg::with_name("danger_accept_invalid_certs")
+                .long("danger-accept-invalid-certs")
+                .help("Do not verify the TLS certificate of the Phabricator server. This makes \
+                    the conduit token available to anybody able to intercept the connection")
+        )
+        .arg(
+            clap::Arg::with_name("preflight")
+                .long("preflight")
+                .help("Check that Phabricator is reachable and accepts the API token before \
+                    running anything")
+        )
+        .arg(
+            clap::Arg::with_name("user_agent")
+                .long("user-agent")
+                .help("The User-Agent of the conduit requests, by default \
+                    `cargo-phabricator/VERSION`")
+                .value_name("STRING")
+                .takes_value(true)
+                .validator(|v| reqwest::header::HeaderValue::from_str(&v)
+                    .map(|_| ())
+                    .map_err(|e| e.to_string()))
+        )
+        .arg(
+            clap::Arg::with_name("header")
+                .long("header")
+                .help("Add a header to the conduit requests, e.g. for an authenticating proxy in \
+                    front of Phabricator")
+                .value_name("NAME: VALUE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|v| header(&v).map(|_| ()))
+        )
+        .arg(
+            clap::Arg::with_name("conduit_retries")
+                .long("conduit-retries")
+                .help("How many times to retry a conduit request that failed due to a connection \
+                    or server error")
+                .takes_value(true)
+                .default_value("3")
+                .validator(|v| v.parse::<u32>().map(|_| ()).map_err(|e| e.to_string()))
         )
         .subcommand(fmt_subcommand)
         .subcommand(check_subcommand)
         .subcommand(build_subcommand)
         .subcommand(test_subcommand)
-        .subcommand(clippy_subcommand);
+        .subcommand(bench_subcommand)
+        .subcommand(clippy_subcommand)
+        .subcommand(reset_subcommand);
 
     let matches = cli.get_matches();
-    let result: Result<(), Box<dyn std::error::Error>> = tokio::runtime::Builder::new()
+    let result: Result<(), MainError> = tokio::runtime::Builder::new()
         .basic_scheduler()
         .enable_all()
         .build()
         .map_err(Into::into)
         .and_then(|mut runtime| runtime.block_on(async {
-            let arcconfig = crate::arcconfig::find().map_err(FindArcConfigError)?;
-            let phab_uri = matches.value_of("phabricator_uri")
-                .or(arcconfig.phab_uri.as_ref().map(|x| &x[..]))
-                .ok_or(GetLocationError)?;
-            let build_phid = matches.value_of("build_phid")
-                .ok_or(GetBuildPhidError)?;
-            let token = matches.value_of("conduit_token")
-                .ok_or(GetConduitTokenError)?;
+            let deadline = matches.value_of("deadline").map(|v| {
+                let seconds = v.parse().expect("validated by clap");
+                (tokio::time::Instant::now() + std::time::Duration::from_secs(seconds), seconds)
+            });
+            let verbosity = if matches.is_present("quiet") {
+                Verbosity::Quiet
+            } else {
+                Verbosity::Normal
+            };
+            let output_format = match matches.value_of("output_format") {
+                Some("json") => phab::OutputFormat::Json,
+                _ => phab::OutputFormat::Human,
+            };
+            let log = phab::Log::new(verbosity, output_format);
+            // Find out whether cargo can be run at all, rather than failing when its output is
+            // expected. Neither `reset` nor `test --junit` run it.
+            let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+            let runs_cargo = match matches.subcommand() {
+                ("reset", _) => false,
+                ("test", Some(args)) => !args.is_present("test_junit"),
+                _ => true,
+            };
+            if runs_cargo {
+                tokio::process::Command::new(&cargo)
+                    .arg("--version")
+                    .stdout(std::process::Stdio::null())
+                    .status()
+                    .await
+                    .map_err(|e| FindCargoError(e, cargo.clone()))?;
+            }
+            let manifest_path = match matches.value_of_os("manifest_path") {
+                Some(path) => {
+                    let path = std::path::Path::new(path);
+                    let path = path.canonicalize().map(paths::simplified)
+                        .map_err(|e| FindManifestError(e, path.into()))?;
+                    if !path.is_file() || path.file_name() != Some("Cargo.toml".as_ref()) {
+                        return Err(NotAManifestError(path).into());
+                    }
+                    Some(path)
+                }
+                None => None,
+            };
+            let arcconfig = crate::arcconfig::find(manifest_path.as_ref().and_then(|p| p.parent()))
+                .map_err(FindArcConfigError)?;
+            // Created up front, so that the payloads of this run are not appended to those of an
+            // earlier one.
+            let save_payload = matches.value_of_os("save_payload").map(std::path::PathBuf::from);
+            if let Some(path) = &save_payload {
+                std::fs::File::create(path).map_err(|e| CreatePayloadFileError(e, path.clone()))?;
+            }
+            let repo_root = match matches.value_of_os("repo_root") {
+                Some(path) => std::path::Path::new(path).canonicalize().map(paths::simplified)
+                    .map_err(|e| FindRepoRootError(e, path.into()))?,
+                None => arcconfig.location,
+            };
+            let changed_lines = if matches.is_present("changed_only") {
+                Some(match matches.value_of_os("diff") {
+                    Some(path) => diff::ChangedLines::parse(
+                        &std::fs::read_to_string(path)
+                            .map_err(|e| diff::Error::Read(e, path.into()))?
+                    ),
+                    None => diff::git_changes(&repo_root, matches.value_of("base_ref")).await?,
+                })
+            } else {
+                None
+            };
+            let deny = matches.values_of("deny").into_iter().flatten().map(String::from)
+                .collect::<Vec<_>>();
+            let env = matches.values_of("env")
+                .into_iter()
+                .flatten()
+                .map(|v| env_var(v).expect("validated by clap"))
+                .collect::<Vec<_>>();
+            let mut target_rustflags = Vec::new();
+            let in_env = |name: &str| {
+                env.iter().any(|(k, _)| k == name) || std::env::var_os(name).is_some()
+            };
+            // The configuration is only used without the variables.
+            if !deny.is_empty() && !in_env("RUSTFLAGS") && !in_env("CARGO_ENCODED_RUSTFLAGS") {
+                if let Ok(cwd) = std::env::current_dir() {
+                    target_rustflags = cargo_config::target_rustflags(&cwd);
+                }
+                // Only the variable of the `--target` can be told apart from the others, without
+                // asking rustc for the host.
+                let names = std::env::vars_os().filter_map(|(k, _)| k.into_string().ok())
+                    .chain(env.iter().map(|(k, _)| k.clone()))
+                    .collect::<std::collections::BTreeSet<_>>();
+                for name in names {
+                    if !name.starts_with("CARGO_TARGET_") || !name.ends_with("_RUSTFLAGS") {
+                        continue;
+                    }
+                    match matches.value_of("target") {
+                        Some(target) if name == cargo_config::target_rustflags_var(target) => {
+                            if !target_rustflags.iter().any(|key| key == target) {
+                                target_rustflags.push(String::from(target));
+                            }
+                        }
+                        _ => log.warn(format!("--deny does not add to {}", name)),
+                    }
+                }
+            }
+            // The `~/.arcrc` is only read if a value falls back to it, so that a broken one does
+            // not matter when everything is given explicitly.
+            let loaded_arcrc = std::cell::OnceCell::<Option<arcconfig::ArcRc>>::new();
+            let arcrc = || -> Result<Option<&arcconfig::ArcRc>, LoadArcRcError> {
+                if let Some(arcrc) = loaded_arcrc.get() {
+                    return Ok(arcrc.as_ref());
+                }
+                let arcrc = crate::arcconfig::arcrc().map_err(LoadArcRcError)?;
+                Ok(loaded_arcrc.get_or_init(|| arcrc).as_ref())
+            };
+            let dry_run = matches.is_present("dry_run");
+            let no_publish = matches.is_present("no_publish");
+            let mut reporters = Vec::<Box<dyn report::Reporter>>::new();
+            for sink in matches.values_of_os("report_to").into_iter().flatten() {
+                match sink.to_str() {
+                    Some("conduit") => reporters.push(Box::new(report::Conduit)),
+                    Some("stdout") => reporters.push(Box::new(report::JsonLines::stdout())),
+                    _ => {
+                        let path = std::path::Path::new(sink);
+                        reporters.push(Box::new(report::JsonLines::create(path)
+                            .map_err(|e| CreateReportFileError(e, path.into()))?));
+                    }
+                }
+            }
+            // Conduit is only needed to publish to Harbormaster.
+            let to_conduit = !matches.is_present("report_to")
+                || matches.values_of_os("report_to").into_iter().flatten().any(|s| s == "conduit");
+            if !matches.is_present("report_to") {
+                reporters.push(Box::new(report::Conduit));
+            }
+            let unpublished = dry_run || no_publish || !to_conduit;
+            let explicit_conduit_uri = matches.value_of("conduit_uri")
+                .map(|uri| String::from(uri.trim_end_matches('/')));
+            let phab_uri = value_or_env(&matches, "phabricator_uri", "phabricator_uri_env")
+                .or(arcconfig.phab_uri);
+            let phab_uri = match phab_uri {
+                None if explicit_conduit_uri.is_none() => arcrc()?.and_then(|a| a.phab_uri()),
+                uri => uri,
+            };
+            let phab_uri = phab_uri.map(|uri| format!("{}/api", uri.trim_end_matches('/')));
+            let conduit_uri = explicit_conduit_uri.or(phab_uri)
+                .or_else(|| if unpublished { Some(String::new()) } else { None })
+                .ok_or_else(|| GetLocationError(env_name(&matches, "phabricator_uri_env")))?;
+            // Each subcommand produces either lints or test results.
+            let kind = match matches.subcommand_name() {
+                Some("test") | Some("bench") => "test",
+                _ => "lint",
+            };
+            let build_phid = build_phid_for(&matches, kind)
+                .or_else(|| std::env::var(env_name(&matches, "build_phid_env")).ok())
+                .or_else(|| harbormaster_build_phid(&matches))
+                .or_else(|| if unpublished { Some(String::new()) } else { None })
+                .ok_or_else(|| GetBuildPhidError(env_name(&matches, "build_phid_env")))?;
+            let token = conduit_token_file(&matches)?
+                .or_else(|| value_or_env(&matches, "conduit_token", "conduit_token_env"));
+            let token = match token {
+                None => arcrc()?.and_then(|a| a.token(&conduit_uri)),
+                token => token,
+            };
+            let token = token
+                .or_else(|| if unpublished { Some(String::new()) } else { None })
+                .ok_or_else(|| GetConduitTokenError(env_name(&matches, "conduit_token_env")))?;
+            let conduit_timeout = matches.value_of("conduit_timeout")
+                .expect("has a default value")
+                .parse()
+                .expect("validated by clap");
+            // Unless a --proxy is given, the HTTP_PROXY, HTTPS_PROXY and NO_PROXY environment
+            // variables are used by reqwest.
+            let user_agent = matches.value_of("user_agent").unwrap_or(DEFAULT_USER_AGENT);
+            let mut client = reqwest::Client::builder()
+                .timeout(std::time::Duration::from_secs(conduit_timeout))
+                .user_agent(user_agent);
+            // The headers an authenticating proxy in front of Phabricator may require.
+            let mut headers = reqwest::header::HeaderMap::new();
+            for value in matches.values_of("header").into_iter().flatten() {
+                let (name, value) = header(value).expect("validated by clap");
+                headers.append(name, value);
+            }
+            client = client.default_headers(headers);
+            if let Some(proxy) = matches.value_of("proxy") {
+                client = client.proxy(reqwest::Proxy::all(proxy).expect("validated by clap"));
+            }
+            if let Some(path) = matches.value_of_os("cacert") {
+                let path = std::path::PathBuf::from(path);
+                let pem = std::fs::read(&path).map_err(|e| ReadCaCertError(e, path.clone()))?;
+                let cert = reqwest::Certificate::from_pem(&pem)
+                    .map_err(|e| ParseCaCertError(e, path))?;
+                client = client.add_root_certificate(cert);
+            }
+            if matches.is_present("danger_accept_invalid_certs") {
+                log.warn("not verifying the TLS certificate of the Phabricator server");
+                client = client.danger_accept_invalid_certs(true);
+            }
+            let client = client.build().map_err(BuildClientError)?;
 
             let ctxt = Context {
-                phab_uri: String::from(phab_uri),
-                build_phid: String::from(build_phid),
-                token: String::from(token),
-                arcconfig: arcconfig.location,
+                conduit_uri,
+                build_phid,
+                token,
+                repo_root,
+                cargo,
+                manifest_path,
+                offline: matches.is_present("offline"),
+                locked: matches.is_present("locked"),
+                frozen: matches.is_present("frozen"),
+                target: matches.value_of("target").map(String::from),
+                heartbeat: matches.value_of("heartbeat")
+                    .map(|v| std::time::Duration::from_secs(v.parse().expect("validated by clap"))),
+                deadline,
+                client,
+                batch_size: matches.value_of("batch_size")
+                    .expect("has a default value")
+                    .parse()
+                    .expect("validated by clap"),
+                dry_run,
+                no_publish,
+                conduit_retries: matches.value_of("conduit_retries")
+                    .expect("has a default value")
+                    .parse()
+                    .expect("validated by clap"),
+                verbosity,
+                output_format,
+                severity_map: matches.values_of("severity_map")
+                    .into_iter()
+                    .flatten()
+                    .map(|v| severity_mapping(v).expect("validated by clap"))
+                    .collect(),
+                fail_on: matches.value_of("fail_on").map(|v| v.parse().expect("validated by clap")),
+                reporters,
+                save_payload,
+                allow_codes: matches.values_of("allow").into_iter().flatten().map(String::from).collect(),
+                only_codes: matches.values_of("only").into_iter().flatten().map(String::from).collect(),
+                include_paths: path_patterns(&matches, "include_path"),
+                exclude_paths: path_patterns(&matches, "exclude_path"),
+                changed_lines,
+                keep_lineless: !matches.is_present("drop_lineless"),
+                no_rendered: matches.is_present("no_rendered"),
+                lint_links: !matches.is_present("no_lint_links"),
+                env,
+                deny,
+                target_rustflags,
+                summary: Default::default(),
+                metadata: std::sync::OnceLock::new(),
             };
-            match matches.subcommand() {
+            if matches.is_present("preflight") {
+                ctxt.preflight().await?;
+            }
+            let result = match matches.subcommand() {
                 ("fmt", Some(args)) => ctxt.fmt(args).await.map_err(Into::into),
                 ("check", Some(args)) => ctxt.check("check", args).await.map_err(Into::into),
                 ("clippy", Some(args)) => ctxt.check("clippy", args).await.map_err(Into::into),
                 ("build", Some(args)) => ctxt.check("build", args).await.map_err(Into::into),
                 ("test", Some(args)) => ctxt.test(args).await.map_err(Into::into),
+                ("bench", Some(args)) => ctxt.bench(args).await.map_err(Into::into),
+                ("reset", Some(_)) => ctxt.reset().await.map_err(Into::into),
                 (sc, Some(args)) => Err(UnimplementedSubcommand.into()),
                 (sc, None) => panic!("clap did not produce args for {}", sc),
+            };
+            // `reset` publishes no results to summarize.
+            if matches.subcommand_name() != Some("reset") {
+                ctxt.report_summary();
             }
+            result
         }));
 
     std::process::exit(match result {
         Ok(_) => 0,
-        Err(ref error) => {
+        Err(MainError { kind: ErrorKind::User, ref error }) => {
+            // Errors the user can correct are better off being concise.
+            let mut message = error.to_string();
+            let mut source = error.source();
+            while let Some(src) = source {
+                message.push_str(": ");
+                message.push_str(&src.to_string());
+                source = src.source();
+            }
+            eprintln!("error: {}", message);
+            1
+        }
+        Err(MainError { kind: ErrorKind::Internal, ref error }) => {
             eprintln!("error: {}", error);
             let mut source = error.source();
             while let Some(src) = source {
                 eprintln!("  caused by: {}", src);
                 source = src.source();
             }
+            eprintln!(
+                "note: this is likely a bug in cargo-phabricator, please file an issue at \
+                 https://github.com/nagisa/cargo-phabricator/issues"
+            );
             1
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{deny_rustflags, DenyFlags};
+
+    #[test]
+    fn denies_lints_through_the_rustflags_in_use() {
+        let deny = [String::from("warnings"), String::from("clippy::all")];
+        let vars = |vars: &'static [(&'static str, &'static str)]| move |name: &str| {
+            vars.iter().find(|(k, _)| *k == name).map(|(_, v)| v.into())
+        };
+        assert_eq!(deny_rustflags(&[], &[], vars(&[])), None);
+        assert_eq!(
+            deny_rustflags(&deny, &[], vars(&[("CARGO_BUILD_RUSTFLAGS", "--cfg foo")])),
+            Some(DenyFlags::Config(vec![
+                String::from(r#"build.rustflags=["-D","warnings","-D","clippy::all"]"#)
+            ])),
+        );
+        let targets = [String::from("x86_64-unknown-linux-gnu"), String::from("cfg(unix)")];
+        assert_eq!(
+            deny_rustflags(&deny, &targets, vars(&[])),
+            Some(DenyFlags::Config(vec![
+                String::from(r#"build.rustflags=["-D","warnings","-D","clippy::all"]"#),
+                String::from(
+                    r#"target."x86_64-unknown-linux-gnu".rustflags=["-D","warnings","-D","clippy::all"]"#
+                ),
+                String::from(r#"target."cfg(unix)".rustflags=["-D","warnings","-D","clippy::all"]"#),
+            ])),
+        );
+        assert_eq!(
+            deny_rustflags(&deny, &targets, vars(&[("RUSTFLAGS", "")])),
+            Some(DenyFlags::Env("RUSTFLAGS", "-D warnings -D clippy::all".into())),
+        );
+        assert_eq!(
+            deny_rustflags(&deny, &[], vars(&[("RUSTFLAGS", "--cfg foo")])),
+            Some(DenyFlags::Env("RUSTFLAGS", "--cfg foo -D warnings -D clippy::all".into())),
+        );
+        assert_eq!(
+            deny_rustflags(&deny, &[], vars(&[("RUSTFLAGS", "")])),
+            Some(DenyFlags::Env("RUSTFLAGS", "-D warnings -D clippy::all".into())),
+        );
+        assert_eq!(
+            deny_rustflags(&deny, &[], vars(&[
+                ("RUSTFLAGS", "--cfg foo"),
+                ("CARGO_ENCODED_RUSTFLAGS", "--cfg\x1fbar"),
+            ])),
+            Some(DenyFlags::Env(
+                "CARGO_ENCODED_RUSTFLAGS",
+                "--cfg\x1fbar\x1f-D\x1fwarnings\x1f-D\x1fclippy::all".into(),
+            )),
+        );
+    }
+}
@@ -0,0 +1,147 @@
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum Error {
+    #[error("could not run `cargo metadata`")]
+    Run(#[source] std::io::Error),
+    #[error("`cargo metadata` failed with {0}")]
+    ExitStatus(std::process::ExitStatus),
+    #[error("could not parse the output of `cargo metadata`")]
+    Parse(#[source] serde_json::Error),
+}
+
+impl crate::ClassifyError for Error {
+    fn kind(&self) -> crate::ErrorKind {
+        match self {
+            Error::Run(_) => crate::ErrorKind::User,
+            Error::ExitStatus(_) => crate::ErrorKind::User,
+            Error::Parse(_) => crate::ErrorKind::Internal,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct MetadataSchema {
+    pub(crate) packages: Vec<PackageSchema>,
+    pub(crate) workspace_members: Vec<String>,
+    pub(crate) workspace_root: std::path::PathBuf,
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct PackageSchema {
+    pub(crate) id: String,
+    pub(crate) manifest_path: std::path::PathBuf,
+    pub(crate) edition: String,
+    pub(crate) targets: Vec<TargetSchema>,
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct TargetSchema {
+    pub(crate) src_path: std::path::PathBuf,
+    pub(crate) edition: String,
+}
+
+impl MetadataSchema {
+    /// The packages that are members of the workspace, rather than its dependencies.
+    pub(crate) fn workspace_packages(&self) -> impl Iterator<Item=&PackageSchema> {
+        self.packages.iter().filter(move |p| self.workspace_members.contains(&p.id))
+    }
+
+    /// The directory containing the manifest of the package.
+    pub(crate) fn package_dir(&self, package_id: &str) -> Option<&std::path::Path> {
+        self.packages.iter().find(|p| p.id == package_id)?.manifest_path.parent()
+    }
+
+    /// The workspace package whose directory contains the file, the innermost one if the packages
+    /// are nested.
+    pub(crate) fn workspace_package_of(&self, file: &std::path::Path)
+    -> Option<&PackageSchema> {
+        self.workspace_packages()
+            .filter(|p| p.manifest_path.parent().is_some_and(|dir| file.starts_with(dir)))
+            .max_by_key(|p| p.manifest_path.components().count())
+    }
+}
+
+impl crate::Context {
+    /// `cargo metadata` for the workspace, without resolving its dependencies.
+    ///
+    /// Cargo is only run the first time, later calls return the same metadata.
+    pub(crate) async fn metadata(&self) -> Result<&MetadataSchema, Error> {
+        if let Some(metadata) = self.metadata.get() {
+            return Ok(metadata);
+        }
+        let output = self.cargo_command("metadata")
+            .arg("--format-version").arg("1")
+            .arg("--no-deps")
+            .stderr(std::process::Stdio::inherit())
+            .output()
+            .await
+            .map_err(Error::Run)?;
+        if !output.status.success() {
+            return Err(Error::ExitStatus(output.status));
+        }
+        let metadata = serde_json::from_slice(&output.stdout).map_err(Error::Parse)?;
+        Ok(self.metadata.get_or_init(|| metadata))
+    }
+}
+
+/// Extract the package name out of a cargo package ID.
+///
+/// Older cargo versions format these as `name version (source)`, newer ones as
+/// `source#name@version`, or `source#version` where the name is the last component of the
+/// source path.
+pub(crate) fn package_name(package_id: &str) -> &str {
+    if let Some((name, _)) = package_id.split_once(' ') {
+        return name;
+    }
+    match package_id.rsplit_once('#') {
+        Some((_, fragment)) if fragment.contains('@') => {
+            fragment.split('@').next().unwrap_or(fragment)
+        }
+        Some((source, _)) => {
+            let source = source.split('?').next().unwrap_or(source);
+            source.trim_end_matches('/').rsplit('/').next().unwrap_or(source)
+        }
+        None => package_id,
+    }
+}
+
+/// Extract the package version out of a cargo package ID, in any of the formats `package_name`
+/// supports.
+pub(crate) fn package_version(package_id: &str) -> &str {
+    if let Some((_, rest)) = package_id.split_once(' ') {
+        return rest.split(' ').next().unwrap_or(rest);
+    }
+    match package_id.rsplit_once('#') {
+        Some((_, fragment)) => fragment.rsplit('@').next().unwrap_or(fragment),
+        None => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{package_name, package_version};
+
+    #[test]
+    fn parses_package_ids() {
+        let ids = [
+            (
+                "serde 1.0.130 (registry+https://github.com/rust-lang/crates.io-index)",
+                "serde",
+                "1.0.130",
+            ),
+            ("foo 0.1.0 (path+file:///ws/foo)", "foo", "0.1.0"),
+            ("path+file:///ws/crates/foo#bar@0.2.0", "bar", "0.2.0"),
+            (
+                "registry+https://github.com/rust-lang/crates.io-index#serde@1.0.130",
+                "serde",
+                "1.0.130",
+            ),
+            ("path+file:///ws/crates/foo#0.1.0", "foo", "0.1.0"),
+            ("path+file:///ws/crates/foo/#0.1.0", "foo", "0.1.0"),
+            ("git+https://example.com/repo/baz?branch=main#1.2.3", "baz", "1.2.3"),
+        ];
+        for (id, name, version) in ids {
+            assert_eq!(package_name(id), name, "{}", id);
+            assert_eq!(package_version(id), version, "{}", id);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf, Prefix};
+
+/// The `path` relative to `root`, with its components separated by `/` as Phabricator expects.
+///
+/// The paths are compared component by component, the way the platform does. On Windows that is
+/// regardless of case and of the separators used, and a `\\?\C:` prefix is the same as `C:`.
+pub(crate) fn strip_root(path: &Path, root: &Path) -> Option<PathBuf> {
+    strip_root_with(path, root, cfg!(windows))
+}
+
+fn strip_root_with(path: &Path, root: &Path, ignore_case: bool) -> Option<PathBuf> {
+    let mut components = path.components();
+    for expected in root.components() {
+        if !same_component(components.next()?, expected, ignore_case) {
+            return None;
+        }
+    }
+    let mut relative = OsString::new();
+    for component in components {
+        if !relative.is_empty() {
+            relative.push("/");
+        }
+        relative.push(component.as_os_str());
+    }
+    Some(relative.into())
+}
+
+fn same_component(a: Component, b: Component, ignore_case: bool) -> bool {
+    match (a, b) {
+        (Component::Prefix(a), Component::Prefix(b)) => match (disk(a.kind()), disk(b.kind())) {
+            (Some(a), Some(b)) => a.eq_ignore_ascii_case(&b),
+            _ => a == b,
+        },
+        (Component::Normal(a), Component::Normal(b)) if ignore_case => {
+            a.to_string_lossy().to_lowercase() == b.to_string_lossy().to_lowercase()
+        }
+        (a, b) => a == b,
+    }
+}
+
+fn disk(prefix: Prefix) -> Option<u8> {
+    match prefix {
+        Prefix::Disk(disk) | Prefix::VerbatimDisk(disk) => Some(disk),
+        _ => None,
+    }
+}
+
+/// The `path` without the `\\?\` prefix `canonicalize` adds on Windows, which not all the tools
+/// understand.
+pub(crate) fn simplified(path: PathBuf) -> PathBuf {
+    let mut components = path.components();
+    match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::VerbatimDisk(disk) => {
+                let mut simplified = PathBuf::from(format!("{}:", disk as char));
+                simplified.push(components.as_path());
+                simplified
+            }
+            _ => path,
+        },
+        _ => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use super::{simplified, strip_root, strip_root_with};
+
+    fn relative(path: &str, root: &str) -> Option<PathBuf> {
+        strip_root(Path::new(path), Path::new(root))
+    }
+
+    #[test]
+    fn strips_whole_components() {
+        assert_eq!(relative("/repo/src/lib.rs", "/repo"), Some("src/lib.rs".into()));
+        assert_eq!(relative("/repo/src/lib.rs", "/repo/"), Some("src/lib.rs".into()));
+        assert_eq!(relative("/repo//src/./lib.rs", "/repo"), Some("src/lib.rs".into()));
+        assert_eq!(relative("/repository/src/lib.rs", "/repo"), None);
+        assert_eq!(relative("/other/src/lib.rs", "/repo"), None);
+        assert_eq!(relative("src/lib.rs", "/repo"), None);
+    }
+
+    #[test]
+    fn ignores_case_if_asked_to() {
+        let (path, root) = (Path::new("/Repo/Src/lib.rs"), Path::new("/repo"));
+        assert_eq!(strip_root_with(path, root, false), None);
+        assert_eq!(strip_root_with(path, root, true), Some("Src/lib.rs".into()));
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn handles_windows_paths() {
+        assert_eq!(relative(r"c:\Repo/src\lib.rs", r"C:\repo"), Some("src/lib.rs".into()));
+        assert_eq!(relative(r"C:\repo\src\lib.rs", r"\\?\C:\repo"), Some("src/lib.rs".into()));
+        assert_eq!(relative(r"D:\repo\src\lib.rs", r"C:\repo"), None);
+        assert_eq!(simplified(r"\\?\C:\repo".into()), Path::new(r"C:\repo"));
+    }
+
+    #[test]
+    fn keeps_paths_without_verbatim_prefix() {
+        assert_eq!(simplified("/repo/src".into()), Path::new("/repo/src"));
+    }
+}
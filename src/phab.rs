@@ -1,23 +1,145 @@
 use std::borrow::Cow;
-use std::path::Path;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use futures::future::Either;
 
 #[derive(thiserror::Error, Debug)]
 pub(crate) enum Error {
-    #[error("could not send a request to conduit endpoint")]
+    #[error("could not send a request to conduit endpoint{}", timeout_note(.0))]
     MakeRequest(#[source] reqwest::Error),
+    #[error("could not establish a secure connection to the conduit endpoint (see --cacert)")]
+    Tls(#[source] reqwest::Error),
     #[error("conduit responded with a failure code {0}")]
     ResponseCode(reqwest::StatusCode),
-    #[error("could not read the response code for conduit API call")]
+    #[error("could not read the response code for conduit API call{}", timeout_note(.0))]
     GetResponseBody(#[source] reqwest::Error),
-    #[error("could not decode conduit response as JSON")]
-    DecodeResponseJson(#[source] serde_json::Error),
+    #[error("could not decode conduit response as JSON, it starts with: {1:?}")]
+    DecodeResponseJson(#[source] serde_json::Error, String),
     #[error("conduit API request returned a failure: {1}")]
     Api(#[source] Option<Box<dyn std::error::Error>>, String),
+    #[error("conduit did not accept the API token: {1}")]
+    Auth(#[source] Option<Box<dyn std::error::Error>>, String),
     #[error("could not encode the request parameters as JSON")]
     EncodeJson(#[source] serde_json::Error),
+    #[error("could not publish batch {1} of {2}")]
+    Batch(#[source] Box<Error>, usize, usize),
+    #[error("could not read the artifact {1:?}")]
+    ReadArtifact(#[source] std::io::Error, PathBuf),
+    #[error("conduit returned an unexpected result")]
+    DecodeResult(#[source] serde_json::Error),
+    #[error("could not save the payload to --save-payload {1:?}")]
+    SavePayload(#[source] std::io::Error, PathBuf),
+    #[error("could not write the results to --report-to {1}")]
+    WriteReport(#[source] std::io::Error, String),
 }
 
-#[derive(serde::Serialize)]
+impl Error {
+    /// Whether retrying the request might succeed.
+    ///
+    /// Only failures to communicate with the server, and being rate limited, are considered
+    /// transient. Other requests that the server rejects are not going to succeed when repeated.
+    fn is_transient(&self) -> bool {
+        match self {
+            Error::MakeRequest(e) => !e.is_builder(),
+            Error::ResponseCode(status) => status.is_server_error(),
+            Error::GetResponseBody(_) => true,
+            Error::Api(_, code) => RETRIABLE_ERROR_CODES.contains(&&code[..]),
+            _ => false,
+        }
+    }
+
+    /// Whether `harbormaster.createartifact` failed because the build target already has an
+    /// artifact with the key.
+    ///
+    /// Harbormaster enforces the unique keys with the `key_artifact` index, which conduit reports
+    /// as a generic failure with the MySQL error, like `#1062: Duplicate entry '...' for key
+    /// 'key_artifact'`. Newer MySQL versions prefix the index with the table name.
+    fn is_duplicate_artifact(&self) -> bool {
+        let info = match self {
+            Error::Api(Some(info), code) if code == "ERR-CONDUIT-CORE" => info.to_string(),
+            _ => return false,
+        };
+        let index = match info.split_once("Duplicate entry").and_then(|(_, rest)| {
+            rest.rsplit_once(" for key '")
+        }) {
+            Some((_, index)) => index.trim_end().trim_end_matches('\''),
+            None => return false,
+        };
+        index.rsplit('.').next() == Some("key_artifact")
+    }
+}
+
+/// Conduit error codes for requests that may succeed if made again later.
+const RETRIABLE_ERROR_CODES: &[&str] = &["ERR-RATE-LIMITING"];
+
+/// Conduit error codes for a missing, invalid or expired API token.
+const AUTH_ERROR_CODES: &[&str] = &["ERR-INVALID-AUTH", "ERR-INVALID-SESSION"];
+
+/// The start of a response body, short enough to include in an error message.
+fn response_snippet(body: &str) -> String {
+    const LIMIT: usize = 200;
+    let body = body.trim();
+    match body.char_indices().nth(LIMIT) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => String::from(body),
+    }
+}
+
+fn timeout_note(error: &reqwest::Error) -> &'static str {
+    if error.is_timeout() {
+        " (the request timed out, see --conduit-timeout)"
+    } else {
+        ""
+    }
+}
+
+/// Whether the request failed because the TLS handshake did not succeed, most commonly because
+/// the server's certificate is not trusted.
+fn is_tls_error(error: &reqwest::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(error) = source {
+        if error.is::<native_tls::Error>() {
+            return true;
+        }
+        // `io::Error` does not expose the error it wraps as its source.
+        if let Some(inner) = error.downcast_ref::<std::io::Error>().and_then(|e| e.get_ref()) {
+            if inner.is::<native_tls::Error>() {
+                return true;
+            }
+        }
+        source = error.source();
+    }
+    false
+}
+
+impl crate::ClassifyError for Error {
+    fn kind(&self) -> crate::ErrorKind {
+        match self {
+            // Network issues, server failures and rejected requests (e.g. a bad token) are all
+            // issues with the environment.
+            Error::MakeRequest(_) => crate::ErrorKind::User,
+            Error::Tls(_) => crate::ErrorKind::User,
+            Error::ResponseCode(_) => crate::ErrorKind::User,
+            Error::GetResponseBody(_) => crate::ErrorKind::User,
+            Error::Api(..) => crate::ErrorKind::User,
+            Error::Auth(..) => crate::ErrorKind::User,
+            // A response that is not JSON at all, like the error page of a misconfigured proxy,
+            // is not something this tool can do anything about.
+            Error::DecodeResponseJson(e, _) if e.is_syntax() || e.is_eof() => {
+                crate::ErrorKind::User
+            }
+            Error::DecodeResponseJson(..) => crate::ErrorKind::Internal,
+            Error::EncodeJson(_) => crate::ErrorKind::Internal,
+            Error::Batch(e, ..) => e.kind(),
+            Error::ReadArtifact(..) => crate::ErrorKind::User,
+            Error::DecodeResult(_) => crate::ErrorKind::Internal,
+            Error::SavePayload(..) => crate::ErrorKind::User,
+            Error::WriteReport(..) => crate::ErrorKind::User,
+        }
+    }
+}
+
+#[derive(serde::Serialize, Clone, Copy, Debug)]
 #[serde(rename_all = "lowercase")]
 pub(crate) enum Severity {
     Advice,
@@ -39,6 +161,36 @@ impl std::fmt::Display for Severity {
     }
 }
 
+impl Severity {
+    /// How severe the lint is when compared against `--fail-on`.
+    pub(crate) fn rank(self) -> u8 {
+        match self {
+            Severity::Disabled => 0,
+            Severity::Advice | Severity::Autofix => 1,
+            Severity::Warning => 2,
+            Severity::Error => 3,
+        }
+    }
+}
+
+impl std::str::FromStr for Severity {
+    type Err = String;
+    fn from_str(severity: &str) -> Result<Self, String> {
+        match severity {
+            "advice" => Ok(Severity::Advice),
+            "autofix" => Ok(Severity::Autofix),
+            "warning" => Ok(Severity::Warning),
+            "error" => Ok(Severity::Error),
+            "disabled" => Ok(Severity::Disabled),
+            _ => Err(format!(
+                "`{}` is not a Phabricator lint severity, expected one of advice, autofix, \
+                 warning, error or disabled",
+                severity
+            )),
+        }
+    }
+}
+
 #[derive(serde::Serialize)]
 pub(crate) struct Lint {
     pub(crate) name: Cow<'static, str>,
@@ -91,14 +243,104 @@ pub(crate) enum TestResult {
     Unsound,
 }
 
+impl std::fmt::Display for TestResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TestResult::Pass => f.write_str("ok"),
+            TestResult::Fail => f.write_str("FAILED"),
+            TestResult::Skip => f.write_str("skipped"),
+            TestResult::Broken => f.write_str("broken"),
+            TestResult::Unsound => f.write_str("unsound"),
+        }
+    }
+}
+
 #[derive(serde::Serialize, Debug)]
 pub(crate) struct Test {
     pub(crate) name: Cow<'static, str>,
     pub(crate) result: TestResult,
     pub(crate) namespace: Option<Cow<'static, str>>,
     pub(crate) duration: Option<f64>,
-    pub(crate) details: Option<&'static str>,
-    pub(crate) format: Option<&'static str>,
+    pub(crate) details: Option<Cow<'static, str>>,
+    /// How Harbormaster renders the details, `text` or `remarkup`.
+    pub(crate) format: Option<Cow<'static, str>>,
+}
+
+impl Test {
+    pub(crate) fn report(&self) {
+        match &self.namespace {
+            Some(namespace) => println!("test {}::{} ... {}", namespace, self.name, self.result),
+            None => println!("test {} ... {}", self.name, self.result),
+        }
+    }
+}
+
+/// How the lints and tests are reported on the standard output.
+#[derive(Clone, Copy, Default)]
+pub(crate) enum OutputFormat {
+    #[default]
+    Human,
+    /// A JSON object per line, for other tools to consume.
+    Json,
+}
+
+#[derive(serde::Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Report<'a> {
+    Lint(&'a Lint),
+    Test(&'a Test),
+    Published(&'a Published),
+    Warning { message: &'a str },
+}
+
+impl Report<'_> {
+    fn print(&self) {
+        match serde_json::to_string(self) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("warning: could not encode the report as JSON: {}", e),
+        }
+    }
+}
+
+/// Where the warnings about what does not stop the work are reported.
+///
+/// They go to stderr, or to stdout along with the lints and tests with `--output-format json`, and
+/// nowhere with `--quiet`.
+#[derive(Clone, Copy)]
+pub(crate) struct Log {
+    verbosity: crate::Verbosity,
+    output_format: OutputFormat,
+}
+
+impl Log {
+    pub(crate) fn new(verbosity: crate::Verbosity, output_format: OutputFormat) -> Log {
+        Log { verbosity, output_format }
+    }
+
+    pub(crate) fn warn(self, message: impl std::fmt::Display) {
+        if self.verbosity == crate::Verbosity::Quiet {
+            return;
+        }
+        match self.output_format {
+            OutputFormat::Human => eprintln!("warning: {}", message),
+            OutputFormat::Json => Report::Warning { message: &message.to_string() }.print(),
+        }
+    }
+}
+
+/// What came of publishing results to Harbormaster.
+#[derive(serde::Serialize, Debug, Default)]
+pub(crate) struct Published {
+    /// How many messages were published, to conduit or with the other `--report-to`s.
+    pub(crate) messages: usize,
+    /// The result returned for the last of them, `null` unless it was sent to conduit.
+    pub(crate) result: Option<serde_json::Value>,
+}
+
+#[derive(serde::Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MessageType {
+    Work,
 }
 
 #[derive(serde::Serialize)]
@@ -110,40 +352,496 @@ struct ConduitParams<'a> {
 struct Params<'a> {
     #[serde(rename="buildTargetPHID")]
     build_target_phid: &'a str,
+    #[serde(rename="type", skip_serializing_if="Option::is_none")]
+    message_type: Option<MessageType>,
     lint: &'a [Lint],
     unit: &'a [Test],
+    #[serde(rename="__conduit__", skip_serializing_if="Option::is_none")]
+    conduit: Option<ConduitParams<'a>>,
+}
+
+#[derive(serde::Serialize)]
+struct UploadParams<'a> {
+    name: &'a str,
+    data_base64: String,
+    #[serde(rename="__conduit__")]
+    conduit: ConduitParams<'a>,
+}
+
+#[derive(serde::Serialize)]
+struct FileArtifactData<'a> {
+    #[serde(rename="filePHID")]
+    file_phid: &'a str,
+}
+
+#[derive(serde::Serialize)]
+struct CreateArtifactParams<'a> {
+    #[serde(rename="buildTargetPHID")]
+    build_target_phid: &'a str,
+    #[serde(rename="artifactKey")]
+    key: &'a str,
+    #[serde(rename="artifactType")]
+    artifact_type: &'static str,
+    #[serde(rename="artifactData")]
+    data: FileArtifactData<'a>,
     #[serde(rename="__conduit__")]
     conduit: ConduitParams<'a>,
 }
 
 #[derive(serde::Deserialize)]
 struct ResponseSchema {
+    #[serde(default)]
+    result: serde_json::Value,
     error_code: Option<String>,
     error_info: Option<String>,
 }
 
+/// How many lints and tests of each kind were collected, and how long it took.
+#[derive(Debug, Default)]
+pub(crate) struct Summary {
+    errors: usize,
+    warnings: usize,
+    advice: usize,
+    autofix: usize,
+    tests: usize,
+    failed: usize,
+    skipped: usize,
+    /// Running cargo, rustfmt and the tests, not counting the publishing in the meantime.
+    working: std::time::Duration,
+    publishing: std::time::Duration,
+}
+
+impl Summary {
+    fn add(&mut self, lints: &[Lint], tests: &[Test]) {
+        for lint in lints {
+            match lint.severity {
+                Severity::Error => self.errors += 1,
+                Severity::Warning => self.warnings += 1,
+                Severity::Advice => self.advice += 1,
+                Severity::Autofix => self.autofix += 1,
+                Severity::Disabled => {}
+            }
+        }
+        for test in tests {
+            self.tests += 1;
+            match test.result {
+                TestResult::Pass => {}
+                TestResult::Skip => self.skipped += 1,
+                TestResult::Fail | TestResult::Broken | TestResult::Unsound => self.failed += 1,
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // The wording does not depend on the counts, so that scripts can easily parse it.
+        write!(
+            f,
+            "{} errors, {} warnings, {} advice, {} autofix; {} tests ({} failed, {} skipped); \
+             {:.1}s working, {:.1}s publishing",
+            self.errors, self.warnings, self.advice, self.autofix,
+            self.tests, self.failed, self.skipped,
+            self.working.as_secs_f64(), self.publishing.as_secs_f64()
+        )
+    }
+}
+
+/// Completes once the process is asked to stop, with SIGINT or SIGTERM.
+///
+/// Signals no longer stop the process by themselves once this is first waited for, but conduit
+/// requests do not take longer than the `--conduit-timeout` either.
+async fn interrupted() -> crate::InterruptedError {
+    let interrupt = async {
+        match tokio::signal::ctrl_c().await {
+            Ok(()) => crate::InterruptedError("SIGINT"),
+            Err(_) => futures::future::pending().await,
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+                crate::InterruptedError("SIGTERM")
+            }
+            Err(_) => futures::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = futures::future::pending();
+    futures::pin_mut!(interrupt);
+    futures::pin_mut!(terminate);
+    futures::future::select(interrupt, terminate).await.factor_first().0
+}
 
 impl crate::Context {
+    pub(crate) fn log(&self) -> Log {
+        Log::new(self.verbosity, self.output_format)
+    }
+
+    pub(crate) fn warn(&self, message: impl std::fmt::Display) {
+        self.log().warn(message)
+    }
+
+    pub(crate) fn report_lint(&self, lint: &Lint) {
+        if self.verbosity == crate::Verbosity::Quiet {
+            return;
+        }
+        match self.output_format {
+            OutputFormat::Human => lint.report(),
+            OutputFormat::Json => Report::Lint(lint).print(),
+        }
+    }
+
+    pub(crate) fn report_test(&self, test: &Test) {
+        if self.verbosity == crate::Verbosity::Quiet {
+            return;
+        }
+        match self.output_format {
+            OutputFormat::Human => test.report(),
+            OutputFormat::Json => Report::Test(test).print(),
+        }
+    }
+
+    /// Report what came of publishing the results, only with `--output-format json`.
+    pub(crate) fn report_published(&self, published: &Published) {
+        if self.verbosity == crate::Verbosity::Quiet {
+            return;
+        }
+        match self.output_format {
+            OutputFormat::Human => {}
+            OutputFormat::Json => Report::Published(published).print(),
+        }
+    }
+
+    /// Add the lints and tests to the summary, whether or not they are published.
+    pub(crate) fn count(&self, lints: &[Lint], tests: &[Test]) {
+        self.summary.lock().expect("not poisoned").add(lints, tests);
+    }
+
+    /// Print how many lints and tests there were in total, to stderr.
+    pub(crate) fn report_summary(&self) {
+        if self.verbosity == crate::Verbosity::Quiet {
+            return;
+        }
+        eprintln!("summary: {}", self.summary.lock().expect("not poisoned"));
+    }
+
     pub(crate) async fn publish_work(
         &self,
+        message_type: Option<MessageType>,
         lints: &[Lint],
         tests: &[Test]
-    ) -> Result<(), Error> {
+    ) -> Result<Published, Error> {
+        self.send_batched(message_type, lints, tests).await
+    }
+
+    /// Publish results while more are still to come.
+    pub(crate) async fn publish_partial_work(
+        &self,
+        lints: &[Lint],
+        tests: &[Test]
+    ) -> Result<Published, Error> {
+        if lints.is_empty() && tests.is_empty() {
+            return Ok(Published::default());
+        }
+        self.send_batched(Some(MessageType::Work), lints, tests).await
+    }
+
+    async fn send_batched(
+        &self,
+        final_type: Option<MessageType>,
+        lints: &[Lint],
+        tests: &[Test]
+    ) -> Result<Published, Error> {
+        // Large payloads may exceed the server's request size limits, so split them up into
+        // multiple `work` messages. Only the final message completes the work.
+        let batches: Vec<(&[Lint], &[Test])> = lints.chunks(self.batch_size)
+            .map(|lints| (lints, &[][..]))
+            .chain(tests.chunks(self.batch_size).map(|tests| (&[][..], tests)))
+            .collect();
+        self.count(lints, tests);
+        let started = std::time::Instant::now();
+        let result = self.send_batches(final_type, batches).await;
+        self.summary.lock().expect("not poisoned").publishing += started.elapsed();
+        result
+    }
+
+    async fn send_batches(
+        &self,
+        final_type: Option<MessageType>,
+        batches: Vec<(&[Lint], &[Test])>,
+    ) -> Result<Published, Error> {
+        let mut published = Published::default();
+        if batches.len() <= 1 {
+            let (lints, tests) = batches.into_iter().next().unwrap_or((&[], &[]));
+            if let Some(result) = self.send_message(final_type, lints, tests).await? {
+                published.messages += 1;
+                published.result = Some(result);
+            }
+            return Ok(published);
+        }
+        let count = batches.len();
+        for (index, (lints, tests)) in batches.into_iter().enumerate() {
+            let message_type = if index + 1 == count { final_type } else { Some(MessageType::Work) };
+            let result = self.send_message(message_type, lints, tests).await
+                .map_err(|e| Error::Batch(Box::new(e), index + 1, count))?;
+            if let Some(result) = result {
+                published.messages += 1;
+                published.result = Some(result);
+            }
+        }
+        Ok(published)
+    }
+
+    /// Run `work`, periodically letting Harbormaster know the build is still making progress.
+    ///
+    /// The heartbeat stops as soon as `work` completes, so the caller can publish the final
+    /// results without them racing against a heartbeat message. Once the `--deadline` passes,
+    /// or the process is asked to stop with SIGINT or SIGTERM, `work` is cancelled and the results
+    /// it collected so far are left for the caller to publish. The commands it runs are killed as
+    /// it is dropped.
+    pub(crate) async fn with_heartbeat<F, T, E>(&self, work: F) -> Result<T, E>
+    where F: Future<Output=Result<T, E>>,
+          E: From<crate::DeadlineError> + From<crate::InterruptedError>,
+    {
+        let published_before = self.summary.lock().expect("not poisoned").publishing;
+        let started = std::time::Instant::now();
+        let output = self.with_heartbeat_inner(work).await;
+        let mut summary = self.summary.lock().expect("not poisoned");
+        let published = summary.publishing - published_before;
+        summary.working += started.elapsed().saturating_sub(published);
+        output
+    }
+
+    async fn with_heartbeat_inner<F, T, E>(&self, work: F) -> Result<T, E>
+    where F: Future<Output=Result<T, E>>,
+          E: From<crate::DeadlineError> + From<crate::InterruptedError>,
+    {
+        let work = async {
+            let work = async {
+                match self.deadline {
+                    Some((deadline, seconds)) => tokio::time::timeout_at(deadline, work).await
+                        .unwrap_or_else(|_| Err(crate::DeadlineError(seconds).into())),
+                    None => work.await,
+                }
+            };
+            let interrupted = interrupted();
+            futures::pin_mut!(work);
+            futures::pin_mut!(interrupted);
+            match futures::future::select(work, interrupted).await {
+                Either::Left((output, _)) => output,
+                Either::Right((error, _)) => Err(error.into()),
+            }
+        };
+        let period = match self.heartbeat {
+            Some(period) if !self.no_publish => period,
+            _ => return work.await,
+        };
+        let heartbeat = async {
+            // Let Harbormaster know the work has started, rather than the build appearing stalled
+            // until the first heartbeat.
+            if let Err(e) = self.send_message(Some(MessageType::Work), &[], &[]).await {
+                self.warn(format_args!("could not send a heartbeat: {}", e));
+            }
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.send_message(Some(MessageType::Work), &[], &[]).await {
+                    self.warn(format_args!("could not send a heartbeat: {}", e));
+                }
+            }
+        };
+        futures::pin_mut!(work);
+        futures::pin_mut!(heartbeat);
+        match futures::future::select(work, heartbeat).await {
+            Either::Left((output, _)) => output,
+            Either::Right(_) => unreachable!("heartbeat never completes"),
+        }
+    }
+
+    /// `None` if the message was not actually sent, due to `--dry-run` or `--no-publish`.
+    async fn send_message(
+        &self,
+        message_type: Option<MessageType>,
+        lints: &[Lint],
+        tests: &[Test]
+    ) -> Result<Option<serde_json::Value>, Error> {
         let params = Params {
             build_target_phid: &self.build_phid,
+            message_type,
             lint: lints,
             unit: tests,
-            conduit: ConduitParams {
+            conduit: Some(ConduitParams {
                 token: &self.token,
-            },
+            }),
+        };
+        if self.no_publish {
+            return Ok(None);
+        }
+        if let Some(path) = &self.save_payload {
+            self.save_payload(path, &Params { conduit: None, ..params })?;
+        }
+        if self.dry_run {
+            let params = Params { conduit: None, ..params };
+            let json = serde_json::to_string_pretty(&params).map_err(Error::EncodeJson)?;
+            println!("would call harbormaster.sendmessage with:\n{}", json);
+            return Ok(None);
+        }
+        let mut result = None;
+        for reporter in &self.reporters {
+            // The messages have either lints or tests, other than the empty heartbeats.
+            let published = if tests.is_empty() {
+                reporter.publish_lints(self, message_type, lints).await?
+            } else {
+                reporter.publish_tests(self, message_type, tests).await?
+            };
+            // Conduit's result, rather than the `null` of the other reporters.
+            if result.as_ref().is_none_or(serde_json::Value::is_null) {
+                result = published.or(result);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Send the results to the build target with `harbormaster.sendmessage`.
+    pub(crate) async fn send_to_conduit(
+        &self,
+        message_type: Option<MessageType>,
+        lints: &[Lint],
+        tests: &[Test]
+    ) -> Result<serde_json::Value, Error> {
+        let params = Params {
+            build_target_phid: &self.build_phid,
+            message_type,
+            lint: lints,
+            unit: tests,
+            conduit: Some(ConduitParams {
+                token: &self.token,
+            }),
+        };
+        let json = serde_json::to_string(&params).map_err(Error::EncodeJson)?;
+        self.call_conduit("harbormaster.sendmessage", &json).await
+    }
+
+    /// Append the payload, without the token, to the `--save-payload` file as a line of JSON.
+    fn save_payload(&self, path: &std::path::Path, params: &Params<'_>) -> Result<(), Error> {
+        use std::io::Write;
+        let mut json = serde_json::to_vec(params).map_err(Error::EncodeJson)?;
+        json.push(b'\n');
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(&json))
+            .map_err(|e| Error::SavePayload(e, path.into()))
+    }
+
+    /// Send an empty `work` message to the build target.
+    ///
+    /// Harbormaster has no way to remove the results already published to a target, so this only
+    /// marks it as in progress again.
+    pub(crate) async fn reset(&self) -> Result<(), Error> {
+        self.publish_work(Some(MessageType::Work), &[], &[]).await.map(drop)
+    }
+
+    /// Check that conduit is reachable and the token is valid.
+    pub(crate) async fn preflight(&self) -> Result<(), crate::PreflightError> {
+        if self.no_publish || self.dry_run {
+            return Ok(());
+        }
+        let params = serde_json::to_string(&serde_json::json!({
+            "__conduit__": ConduitParams { token: &self.token },
+        })).map_err(|e| crate::PreflightError("could not encode the request", Error::EncodeJson(e)))?;
+        self.call_conduit("conduit.ping", &params).await
+            .map_err(|e| crate::PreflightError("could not reach conduit", e))?;
+        self.call_conduit("user.whoami", &params).await
+            .map_err(|e| crate::PreflightError("conduit did not accept the API token", e))?;
+        Ok(())
+    }
+
+    /// Upload a file and register it as an artifact of the build target under `key`.
+    ///
+    /// An artifact with the same key may already exist if the build step is being retried, in
+    /// which case it is left as is.
+    pub(crate) async fn create_artifact(&self, key: &str, path: &Path) -> Result<(), Error> {
+        if self.no_publish {
+            return Ok(());
+        }
+        if self.dry_run {
+            println!("would upload {:?} as the artifact {:?}", path, key);
+            return Ok(());
+        }
+        let data = std::fs::read(path).map_err(|e| Error::ReadArtifact(e, path.into()))?;
+        let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_else(|| key.into());
+        self.upload_artifact(key, &name, &data).await
+    }
+
+    /// Like `create_artifact`, for a file named `name` with the contents `data`.
+    pub(crate) async fn create_data_artifact(&self, key: &str, name: &str, data: &[u8])
+    -> Result<(), Error> {
+        if self.no_publish {
+            return Ok(());
+        }
+        if self.dry_run {
+            println!("would upload {:?} as the artifact {:?}", name, key);
+            return Ok(());
+        }
+        self.upload_artifact(key, name, data).await
+    }
+
+    async fn upload_artifact(&self, key: &str, name: &str, data: &[u8]) -> Result<(), Error> {
+        let params = UploadParams {
+            name,
+            data_base64: base64::encode(data),
+            conduit: ConduitParams { token: &self.token },
+        };
+        let json = serde_json::to_string(&params).map_err(Error::EncodeJson)?;
+        let result = self.call_conduit("file.upload", &json).await?;
+        let file_phid: String = serde_json::from_value(result).map_err(Error::DecodeResult)?;
+
+        let params = CreateArtifactParams {
+            build_target_phid: &self.build_phid,
+            key,
+            artifact_type: "file",
+            data: FileArtifactData { file_phid: &file_phid },
+            conduit: ConduitParams { token: &self.token },
         };
         let json = serde_json::to_string(&params).map_err(Error::EncodeJson)?;
-        let response = reqwest::Client::new()
-            .post(&format!("{}/api/harbormaster.sendmessage", self.phab_uri))
-            .form(&[("params", json)])
+        match self.call_conduit("harbormaster.createartifact", &json).await {
+            Err(e) if e.is_duplicate_artifact() => {
+                self.warn(format_args!("the artifact {:?} already exists, not replacing it", key));
+                Ok(())
+            }
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// Call a conduit API method, retrying failures that are likely to be transient.
+    async fn call_conduit(&self, method: &str, params: &str) -> Result<serde_json::Value, Error> {
+        let mut attempt = 0;
+        loop {
+            match self.call_conduit_once(method, params).await {
+                Err(e) if attempt < self.conduit_retries && e.is_transient() => {
+                    let delay = std::time::Duration::from_secs(1 << attempt.min(6));
+                    self.warn(format_args!("{}, retrying in {}s", e, delay.as_secs()));
+                    tokio::time::delay_for(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn call_conduit_once(&self, method: &str, params: &str)
+    -> Result<serde_json::Value, Error> {
+        let response = self.client
+            .post(&format!("{}/{}", self.conduit_uri, method))
+            .form(&[("params", params)])
             .send()
             .await
-            .map_err(Error::MakeRequest)?;
+            .map_err(|e| if is_tls_error(&e) { Error::Tls(e) } else { Error::MakeRequest(e) })?;
 
         if !response.status().is_success() {
             return Err(Error::ResponseCode(response.status()));
@@ -151,10 +849,73 @@ impl crate::Context {
 
         let response_body = response.text().await.map_err(Error::GetResponseBody)?;
         let response: ResponseSchema = serde_json::from_str(&response_body)
-            .map_err(Error::DecodeResponseJson)?;
+            .map_err(|e| Error::DecodeResponseJson(e, response_snippet(&response_body)))?;
         if let Some(code) = response.error_code {
-            return Err(Error::Api(response.error_info.map(Into::into), code));
+            let info = response.error_info.map(Into::into);
+            if AUTH_ERROR_CODES.contains(&&code[..]) {
+                return Err(Error::Auth(info, code));
+            }
+            return Err(Error::Api(info, code));
         }
-        Ok(())
+        Ok(response.result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, Lint, Severity, Test, TestResult};
+
+    fn api_error(code: &str, info: &str) -> Error {
+        Error::Api(Some(info.into()), code.into())
+    }
+
+    #[test]
+    fn recognizes_duplicate_artifacts() {
+        let duplicate = "#1062: Duplicate entry 'file-abcdefghijkl' for key 'key_artifact'";
+        assert!(api_error("ERR-CONDUIT-CORE", duplicate).is_duplicate_artifact());
+        assert!(api_error(
+            "ERR-CONDUIT-CORE",
+            "#1062: Duplicate entry 'file-abcdefghijkl' for key \
+             'harbormaster_buildartifact.key_artifact'",
+        ).is_duplicate_artifact());
+        // Other unique keys, e.g. of the uploaded file, are real failures.
+        assert!(!api_error(
+            "ERR-CONDUIT-CORE",
+            "#1062: Duplicate entry 'PHID-FILE-1' for key 'key_phid'",
+        ).is_duplicate_artifact());
+        assert!(!api_error("ERR-CONDUIT-CORE", "Duplicate entry").is_duplicate_artifact());
+        assert!(!api_error("ERR-CONDUIT-CALL", duplicate).is_duplicate_artifact());
+        assert!(!Error::Api(None, "ERR-CONDUIT-CORE".into()).is_duplicate_artifact());
+    }
+
+    #[test]
+    fn counts_results_that_are_not_published() {
+        let ctxt = crate::Context { no_publish: true, batch_size: 1, ..Default::default() };
+        let lint = |severity| Lint {
+            name: "lint".into(),
+            code: "CODE".into(),
+            severity,
+            path: std::path::Path::new("src/lib.rs").into(),
+            description: None,
+            line: None,
+            column: None,
+        };
+        let test = |result| Test {
+            name: "test".into(),
+            result,
+            namespace: None,
+            duration: None,
+            details: None,
+            format: None,
+        };
+        let lints = [lint(Severity::Error), lint(Severity::Warning), lint(Severity::Warning)];
+        let tests = [test(TestResult::Pass), test(TestResult::Fail), test(TestResult::Skip)];
+        let published = futures::executor::block_on(ctxt.publish_work(None, &lints, &tests));
+        assert_eq!(published.unwrap().messages, 0);
+        let summary = ctxt.summary.lock().unwrap().to_string();
+        assert_eq!(
+            summary.split("; ").take(2).collect::<Vec<_>>(),
+            ["1 errors, 2 warnings, 0 advice, 0 autofix", "3 tests (1 failed, 1 skipped)"]
+        );
     }
 }
@@ -0,0 +1,124 @@
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use crate::phab::{Error, Lint, MessageType, Test};
+
+/// Somewhere the lints and tests are published to, chosen with `--report-to`.
+///
+/// The `message_type` is `work` while more results are still to come. Either resolves to the
+/// result of the message once it is published, `null` unless it was sent to conduit.
+pub(crate) trait Reporter: Send + Sync {
+    fn publish_lints<'a>(
+        &'a self,
+        ctxt: &'a crate::Context,
+        message_type: Option<MessageType>,
+        lints: &'a [Lint],
+    ) -> LocalBoxFuture<'a, Result<Option<serde_json::Value>, Error>>;
+
+    fn publish_tests<'a>(
+        &'a self,
+        ctxt: &'a crate::Context,
+        message_type: Option<MessageType>,
+        tests: &'a [Test],
+    ) -> LocalBoxFuture<'a, Result<Option<serde_json::Value>, Error>>;
+}
+
+/// Publishes to the build target with `harbormaster.sendmessage`.
+pub(crate) struct Conduit;
+
+impl Reporter for Conduit {
+    fn publish_lints<'a>(
+        &'a self,
+        ctxt: &'a crate::Context,
+        message_type: Option<MessageType>,
+        lints: &'a [Lint],
+    ) -> LocalBoxFuture<'a, Result<Option<serde_json::Value>, Error>> {
+        ctxt.send_to_conduit(message_type, lints, &[]).map(|r| r.map(Some)).boxed_local()
+    }
+
+    fn publish_tests<'a>(
+        &'a self,
+        ctxt: &'a crate::Context,
+        message_type: Option<MessageType>,
+        tests: &'a [Test],
+    ) -> LocalBoxFuture<'a, Result<Option<serde_json::Value>, Error>> {
+        ctxt.send_to_conduit(message_type, &[], tests).map(|r| r.map(Some)).boxed_local()
+    }
+}
+
+/// Writes each message as a line of JSON, in the shape of the `harbormaster.sendmessage`
+/// parameters without the API token.
+pub(crate) struct JsonLines {
+    /// The file, or `stdout`, for the errors.
+    name: String,
+    output: std::sync::Mutex<Box<dyn std::io::Write + Send>>,
+}
+
+#[derive(serde::Serialize)]
+struct Message<'a> {
+    #[serde(rename="buildTargetPHID")]
+    build_target_phid: &'a str,
+    #[serde(rename="type", skip_serializing_if="Option::is_none")]
+    message_type: Option<MessageType>,
+    lint: &'a [Lint],
+    unit: &'a [Test],
+}
+
+impl JsonLines {
+    pub(crate) fn stdout() -> JsonLines {
+        JsonLines {
+            name: String::from("stdout"),
+            output: std::sync::Mutex::new(Box::new(std::io::stdout())),
+        }
+    }
+
+    /// Write to the file at `path`, replacing whatever it contained.
+    pub(crate) fn create(path: &std::path::Path) -> std::io::Result<JsonLines> {
+        Ok(JsonLines {
+            name: path.display().to_string(),
+            output: std::sync::Mutex::new(Box::new(std::fs::File::create(path)?)),
+        })
+    }
+
+    fn write(&self, message: &Message) -> Result<Option<serde_json::Value>, Error> {
+        use std::io::Write;
+        let mut json = serde_json::to_vec(message).map_err(Error::EncodeJson)?;
+        json.push(b'\n');
+        let mut output = self.output.lock().expect("not poisoned");
+        output.write_all(&json)
+            .and_then(|()| output.flush())
+            .map_err(|e| Error::WriteReport(e, self.name.clone()))?;
+        Ok(Some(serde_json::Value::Null))
+    }
+}
+
+impl Reporter for JsonLines {
+    fn publish_lints<'a>(
+        &'a self,
+        ctxt: &'a crate::Context,
+        message_type: Option<MessageType>,
+        lints: &'a [Lint],
+    ) -> LocalBoxFuture<'a, Result<Option<serde_json::Value>, Error>> {
+        let message = Message {
+            build_target_phid: &ctxt.build_phid,
+            message_type,
+            lint: lints,
+            unit: &[],
+        };
+        futures::future::ready(self.write(&message)).boxed_local()
+    }
+
+    fn publish_tests<'a>(
+        &'a self,
+        ctxt: &'a crate::Context,
+        message_type: Option<MessageType>,
+        tests: &'a [Test],
+    ) -> LocalBoxFuture<'a, Result<Option<serde_json::Value>, Error>> {
+        let message = Message {
+            build_target_phid: &ctxt.build_phid,
+            message_type,
+            lint: &[],
+            unit: tests,
+        };
+        futures::future::ready(self.write(&message)).boxed_local()
+    }
+}
@@ -1,18 +1,58 @@
 use tokio::process::Command;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use crate::jsonl::FilterReportedExt;
-use futures::{Stream, StreamExt};
+use futures::{FutureExt, Stream, StreamExt};
+use tokio::io::AsyncReadExt;
+use crate::jsonl::StreamValuesError;
 
 #[derive(thiserror::Error, Debug)]
 pub(crate) enum Error {
+    #[error("could not publish test results to phabricator")]
+    PublishTests(#[source] crate::phab::Error),
     #[error("could not get command output")]
     CommandOutput(#[source] crate::jsonl::Error),
-    #[error("could not spawn the test")]
+    #[error("could not run the test")]
     SpawnTest(#[source] std::io::Error),
-    #[error("could not wait for the test")]
-    WaitTest(#[source] std::io::Error),
     #[error("test failed with {0}")]
     TestStatus(std::process::ExitStatus),
+    #[error("test did not finish within {0} seconds")]
+    TestTimeout(u64),
+    #[error("could not load --test-env-file")]
+    EnvFile(#[source] crate::dotenv::Error),
+    #[error("could not find the package directories")]
+    Metadata(#[source] crate::metadata::Error),
+    #[error("could not load --junit report")]
+    Junit(#[source] crate::junit::Error),
+    #[error("{0} of the tests did not pass")]
+    NotPassed(usize),
+    #[error("could not build the tests")]
+    Build(#[source] crate::jsonl::Error),
+    #[error("could not publish the lints from building the tests to phabricator")]
+    PublishLints(#[source] crate::phab::Error),
+    #[error(transparent)]
+    Deadline(#[from] crate::DeadlineError),
+    #[error(transparent)]
+    Interrupted(#[from] crate::InterruptedError),
+}
+
+impl crate::ClassifyError for Error {
+    fn kind(&self) -> crate::ErrorKind {
+        match self {
+            Error::PublishTests(e) => e.kind(),
+            Error::CommandOutput(e) => e.kind(),
+            Error::SpawnTest(_) => crate::ErrorKind::User,
+            Error::TestStatus(_) => crate::ErrorKind::User,
+            Error::TestTimeout(_) => crate::ErrorKind::User,
+            Error::EnvFile(e) => e.kind(),
+            Error::Metadata(e) => e.kind(),
+            Error::Junit(e) => e.kind(),
+            Error::NotPassed(_) => crate::ErrorKind::User,
+            Error::Build(e) => e.kind(),
+            Error::PublishLints(e) => e.kind(),
+            Error::Deadline(e) => e.kind(),
+            Error::Interrupted(e) => e.kind(),
+        }
+    }
 }
 
 #[derive(serde::Deserialize)]
@@ -22,7 +62,8 @@ struct ProfileSchema {
 
 #[derive(serde::Deserialize)]
 struct TargetSchema {
-    src_path: PathBuf,
+    name: String,
+    kind: Vec<String>,
 }
 
 #[derive(serde::Deserialize)]
@@ -33,81 +74,872 @@ struct ArtifactSchema {
     package_id: String,
 }
 
+#[derive(serde::Deserialize)]
+struct BuildScriptSchema {
+    package_id: String,
+    out_dir: PathBuf,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+enum CargoMessageSchema {
+    CompilerMessage(crate::check::LintSchema),
+    CompilerArtifact(ArtifactSchema),
+    BuildScriptExecuted(BuildScriptSchema),
+    #[serde(other)]
+    Other,
+}
+
+/// What else cargo built for a package, which its tests can find through the environment.
+#[derive(Default)]
+struct PackageOutputs {
+    out_dir: Option<PathBuf>,
+    executables: Vec<(String, PathBuf)>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TestEvent {
+    Ok,
+    Failed,
+    Ignored,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(serde::Deserialize)]
+struct TestEventSchema {
+    #[serde(rename = "type")]
+    kind: String,
+    /// Missing for benchmark results.
+    event: Option<TestEvent>,
+    name: Option<String>,
+    exec_time: Option<f64>,
+    stdout: Option<String>,
+    message: Option<String>,
+    /// Nanoseconds per iteration of a benchmark.
+    median: Option<f64>,
+    deviation: Option<f64>,
+}
+
+/// The environment `cargo test` runs the test binaries with.
+fn cargo_env(
+    cargo: &std::ffi::OsStr,
+    artifact: &ArtifactSchema,
+    package_dir: Option<&Path>,
+    outputs: &PackageOutputs,
+) -> Vec<(String, std::ffi::OsString)> {
+    let version = crate::metadata::package_version(&artifact.package_id);
+    let (version_core, pre) = version.split_once('-').unwrap_or((version, ""));
+    let mut parts = version_core.split('.');
+    let mut env = vec![
+        (String::from("CARGO"), cargo.into()),
+        (String::from("CARGO_PKG_NAME"), crate::metadata::package_name(&artifact.package_id).into()),
+        (String::from("CARGO_PKG_VERSION"), version.into()),
+        (String::from("CARGO_PKG_VERSION_MAJOR"), parts.next().unwrap_or("").into()),
+        (String::from("CARGO_PKG_VERSION_MINOR"), parts.next().unwrap_or("").into()),
+        (String::from("CARGO_PKG_VERSION_PATCH"), parts.next().unwrap_or("").into()),
+        (String::from("CARGO_PKG_VERSION_PRE"), pre.into()),
+        (String::from("CARGO_CRATE_NAME"), artifact.target.name.replace('-', "_").into()),
+    ];
+    if let Some(manifest_dir) = package_dir {
+        env.push((String::from("CARGO_MANIFEST_DIR"), manifest_dir.into()));
+    }
+    if let Some(out_dir) = &outputs.out_dir {
+        env.push((String::from("OUT_DIR"), out_dir.into()));
+    }
+    for (name, executable) in &outputs.executables {
+        env.push((format!("CARGO_BIN_EXE_{}", name), executable.into()));
+    }
+    env
+}
+
+/// The `cargo test` arguments to run the doctests of a single package with.
+///
+/// Package selection is dropped, as the package is selected explicitly. Returns `None` if the
+/// arguments select specific targets, in which case cargo would not run the doctests anyway.
+fn doctest_cargo_args(cargo_args: &[std::ffi::OsString]) -> Option<Vec<std::ffi::OsString>> {
+    const TARGET_SELECTION: &[&str] = &[
+        "--lib", "--bin", "--bins", "--test", "--tests", "--example", "--examples", "--bench",
+        "--benches", "--all-targets",
+    ];
+    let mut result = Vec::new();
+    let mut args = cargo_args.iter();
+    while let Some(arg) = args.next() {
+        let flag = arg.to_str().map(|a| a.split('=').next().unwrap_or(a));
+        match flag {
+            Some(flag) if TARGET_SELECTION.contains(&flag) => return None,
+            Some("--workspace") | Some("--all") => {}
+            Some(flag @ "-p") | Some(flag @ "--package") | Some(flag @ "--exclude") => {
+                if arg.to_str() == Some(flag) {
+                    args.next();
+                }
+            }
+            _ => result.push(arg.clone()),
+        }
+    }
+    Some(result)
+}
+
+/// Options that apply to running each of the test binaries.
+struct RunOptions {
+    /// Arguments for `cargo test`, from before the `--` separator.
+    cargo_args: Vec<std::ffi::OsString>,
+    /// Arguments for the test harness, from after the `--` separator.
+    harness_args: Vec<std::ffi::OsString>,
+    env: Vec<(String, String)>,
+    output_limit: usize,
+    timeout: Option<std::time::Duration>,
+    /// Whether the binaries are run as benchmarks, rather than tests.
+    bench: bool,
+    /// Whether the tests are only built, with `--no-run-tests`.
+    no_run: bool,
+    /// Whether a harness refused the JSON output, so that the others are only run once, with the
+    /// pretty output.
+    pretty_only: std::sync::atomic::AtomicBool,
+}
+
+/// How the test binaries are run.
+enum Runner {
+    /// Directly, as they are built for the host.
+    Host,
+    /// Through the `CARGO_TARGET_<TRIPLE>_RUNNER` command, such as an emulator.
+    Command(Vec<String>),
+    /// Not at all, as they are built for another target and the variable naming the runner is not
+    /// set.
+    Missing(String),
+}
+
+/// Limit the captured output to roughly `limit` bytes, keeping the end where the panic message
+/// usually is.
+fn truncate_output(output: &str, limit: usize) -> String {
+    if output.len() <= limit {
+        return String::from(output);
+    }
+    let mut start = output.len() - limit;
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("[... {} bytes of output truncated ...]\n{}", start, &output[start..])
+}
+
+/// The panic messages on the stderr of a test binary, including the backtraces, by the name of
+/// the test that panicked.
+///
+/// Libtest captures these as the output of the test, unless it is run with `--nocapture`.
+fn stderr_panics(stderr: &str) -> std::collections::HashMap<&str, String> {
+    let mut panics = std::collections::HashMap::new();
+    let mut current: Option<&mut String> = None;
+    for line in stderr.lines() {
+        // `thread 'NAME' panicked at ...`, or `thread 'NAME' (ID) panicked at ...` in newer
+        // versions, the threads being named after the tests.
+        let panicked = line.strip_prefix("thread '")
+            .and_then(|l| l.split_once('\''))
+            .filter(|(_, rest)| rest.contains(" panicked at "))
+            .map(|(name, _)| name);
+        if let Some(name) = panicked {
+            let panic: &mut String = panics.entry(name).or_default();
+            current = Some(panic);
+        } else if line.starts_with("test ") || line.starts_with("thread '") {
+            current = None;
+        }
+        if let Some(panic) = &mut current {
+            panic.push_str(line);
+            panic.push('\n');
+        }
+    }
+    panics
+}
+
+/// A measured benchmark, with its time per iteration as the duration.
+fn bench_result(name: &str, median_ns: f64, details: String) -> crate::phab::Test {
+    crate::phab::Test {
+        name: String::from(name).into(),
+        result: crate::phab::TestResult::Pass,
+        namespace: None,
+        duration: Some(median_ns / 1e9),
+        details: Some(details.into()),
+        format: Some("text".into()),
+    }
+}
+
+/// The result of a finished test or benchmark, `None` for the other events.
+fn event_result(event: TestEventSchema, output_limit: usize) -> Option<crate::phab::Test> {
+    if event.kind == "bench" {
+        let median = event.median?;
+        let details = format!("{:.2} ns/iter (+/- {:.2})", median, event.deviation.unwrap_or(0.0));
+        return Some(bench_result(&event.name?, median, details));
+    }
+    let (result, details) = match event.event.unwrap_or(TestEvent::Other) {
+        TestEvent::Ok => (crate::phab::TestResult::Pass, None),
+        TestEvent::Failed => (crate::phab::TestResult::Fail, event.stdout),
+        // The message is the reason given in `#[ignore = "reason"]`.
+        TestEvent::Ignored => (crate::phab::TestResult::Skip, event.message),
+        TestEvent::Other => return None,
+    };
+    let name = match event.name {
+        Some(name) if event.kind == "test" => name,
+        _ => return None,
+    };
+    // The captured output is shown as it is, rather than rendered as remarkup.
+    let format = details.as_ref().map(|_| "text".into());
+    Some(crate::phab::Test {
+        name: name.into(),
+        result,
+        namespace: None,
+        duration: event.exec_time,
+        details: details.map(|stdout| truncate_output(stdout.trim(), output_limit).into()),
+        format,
+    })
+}
+
+/// Parse the human readable output of a test binary, for toolchains where the JSON output is not
+/// available.
+fn parse_pretty_output(stdout: &[u8], output_limit: usize) -> Vec<crate::phab::Test> {
+    let stdout = String::from_utf8_lossy(stdout);
+    let mut tests = Vec::new();
+    // The output of failed tests is printed after all of the tests have finished, in sections
+    // that start with a `---- NAME stdout ----` header.
+    let mut outputs = std::collections::HashMap::new();
+    let mut current_output: Option<(&str, String)> = None;
+    for line in stdout.lines() {
+        let header = line.strip_prefix("---- ").and_then(|l| l.strip_suffix(" stdout ----"));
+        if header.is_some() || line == "failures:" {
+            if let Some((name, output)) = current_output.take() {
+                outputs.insert(name, output);
+            }
+        }
+        if let Some(name) = header {
+            current_output = Some((name, String::new()));
+        } else if let Some((_, output)) = &mut current_output {
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+
+    for line in stdout.lines() {
+        let (name, outcome) = match line.strip_prefix("test ").and_then(|l| l.rsplit_once(" ... ")) {
+            Some(v) => v,
+            None => continue,
+        };
+        // `bench:       1,234 ns/iter (+/- 56)`
+        if let Some(measurement) = outcome.strip_prefix("bench:") {
+            let measurement = measurement.trim();
+            let median = measurement.split(' ').next()
+                .and_then(|ns| ns.replace(',', "").parse::<f64>().ok());
+            if let Some(median) = median {
+                tests.push(bench_result(name, median, String::from(measurement)));
+            }
+            continue;
+        }
+        let (result, details) = match outcome {
+            "ok" => (crate::phab::TestResult::Pass, None),
+            "FAILED" => (crate::phab::TestResult::Fail, outputs.get(name).map(|o| o.trim())),
+            "ignored" => (crate::phab::TestResult::Skip, None),
+            // Ignored with a reason, `ignored, REASON`.
+            _ => match outcome.strip_prefix("ignored, ") {
+                Some(reason) => (crate::phab::TestResult::Skip, Some(reason)),
+                None => continue,
+            },
+        };
+        tests.push(crate::phab::Test {
+            name: String::from(name).into(),
+            result,
+            namespace: None,
+            duration: None,
+            format: details.map(|_| "text".into()),
+            details: details.map(|details| truncate_output(details, output_limit).into()),
+        });
+    }
+    tests
+}
+
 impl crate::Context {
 
     pub(crate) async fn test(&self, args: &clap::ArgMatches<'_>) -> Result<(), Error> {
+        if let Some(reports) = args.values_of_os("test_junit") {
+            return self.publish_junit(reports).await;
+        }
+        self.run_harnesses(args, false).await
+    }
+
+    /// Publish the results of tests that were run by something else.
+    async fn publish_junit(&self, reports: clap::OsValues<'_>) -> Result<(), Error> {
+        let mut tests = Vec::new();
+        for report in reports {
+            tests.extend(crate::junit::load(Path::new(report)).map_err(Error::Junit)?);
+        }
+        for test in &tests {
+            self.report_test(test);
+        }
+        let published = self.publish_work(None, &[], &tests).await.map_err(Error::PublishTests)?;
+        self.report_published(&published);
+        let not_passed = tests.iter().filter(|t| {
+            matches!(t.result, crate::phab::TestResult::Fail | crate::phab::TestResult::Broken)
+        }).count();
+        if not_passed > 0 {
+            return Err(Error::NotPassed(not_passed));
+        }
+        Ok(())
+    }
+
+    /// Run the benchmarks, reporting their time per iteration as the test duration.
+    pub(crate) async fn bench(&self, args: &clap::ArgMatches<'_>) -> Result<(), Error> {
+        self.run_harnesses(args, true).await
+    }
+
+    async fn run_harnesses(&self, args: &clap::ArgMatches<'_>, bench: bool) -> Result<(), Error> {
+        let mut env = Vec::new();
+        let backtrace = args.value_of("test_backtrace")
+            .or_else(|| if self.var_os("RUST_BACKTRACE").is_none() { Some("1") } else { None });
+        if let Some(backtrace) = backtrace {
+            env.push((String::from("RUST_BACKTRACE"), String::from(backtrace)));
+        }
+        if let Some(path) = args.value_of_os("test_env_file") {
+            env.extend(crate::dotenv::load(Path::new(path)).map_err(Error::EnvFile)?);
+        }
+        // Set after the variables from the file, so that `--env` takes precedence.
+        env.extend(self.env.iter().cloned());
+        let mut raw_args = args.values_of_os("args").into_iter().flatten().map(Into::into);
+        let mut cargo_args: Vec<std::ffi::OsString> = crate::cargo_feature_args(args)
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        cargo_args.extend(crate::check::without_ansi_message_format(
+            raw_args.by_ref().take_while(|arg: &std::ffi::OsString| arg != "--"),
+            self.log(),
+        ));
+        let options = RunOptions {
+            cargo_args,
+            harness_args: raw_args.collect(),
+            env,
+            output_limit: args.value_of("test_output_limit")
+                .expect("has a default value")
+                .parse()
+                .expect("validated by clap"),
+            timeout: args.value_of("test_timeout")
+                .map(|v| std::time::Duration::from_secs(v.parse().expect("validated by clap"))),
+            bench,
+            no_run: args.is_present("no_run_tests"),
+            pretty_only: std::sync::atomic::AtomicBool::new(false),
+        };
+        // Building the tests does not take nextest.
+        let nextest = !bench && !options.no_run && args.value_of("test_runner") == Some("nextest");
+        let nextest = if nextest && !self.has_nextest().await {
+            self.warn("`cargo nextest` is not installed, running the tests with the builtin test \
+                       harness instead");
+            false
+        } else {
+            nextest
+        };
+        if nextest && options.timeout.is_some() {
+            self.warn("--test-timeout does not apply to nextest, configure its slow-timeout \
+                       instead");
+        }
+        let mut results = Vec::new();
+        let result = if nextest {
+            self.with_heartbeat(self.nextest_inner(&mut results, args, &options)).await
+        } else {
+            self.with_heartbeat(self.test_inner(&mut results, args, &options)).await
+        };
+
+        let passed = results.iter()
+            .filter(|t| matches!(t.result, crate::phab::TestResult::Pass))
+            .count();
+        let human = matches!(self.output_format, crate::phab::OutputFormat::Human);
+        if human && self.verbosity > crate::Verbosity::Quiet && !options.no_run {
+            println!(
+                "{} result: {} passed; {} not passed",
+                if bench { "bench" } else { "test" },
+                passed,
+                results.len() - passed
+            );
+        }
+        // The results have already been published as each of the test binaries finished.
+        let published = self.publish_work(None, &[], &[]).await.map_err(Error::PublishTests)?;
+        self.report_published(&published);
+        result
+    }
+
+    async fn test_inner(
+        &self,
+        results: &mut Vec<crate::phab::Test>,
+        args: &clap::ArgMatches<'_>,
+        options: &RunOptions,
+    ) -> Result<(), Error> {
+        // Also needed for the paths in the diagnostics, which are relative to the workspace root.
+        let metadata = self.metadata().await.map_err(Error::Metadata)?;
         // Build tests and collect the artifacts.
-        let mut cmd = Command::new("cargo");
-        cmd.arg("test")
-            .arg("--message-format").arg("json")
-            .arg("--no-run")
-            .kill_on_drop(true);
+        // `cargo bench` builds the targets with benchmarks in the bench profile, and marks them
+        // as test artifacts just like `cargo test` does.
+        let mut cmd = self.cargo_json_command(if options.bench { "bench" } else { "test" });
+        cmd.arg("--no-run").args(&options.cargo_args);
         let mut tests = Vec::new();
-        let mut artifacts = self.get_reason_json_lines(cmd, "compiler-artifact").filter_reported();
-        futures::pin_mut!(artifacts);
-        while let Some(result) = artifacts.next().await {
-            let artifact: ArtifactSchema = result.map_err(Error::CommandOutput)?;
-            if artifact.profile.test {
+        let mut doctests = Vec::new();
+        let mut outputs = std::collections::HashMap::<_, PackageOutputs>::new();
+        let mut lints = crate::check::Lints::default();
+        let mut messages = self.get_stdout_json_lines(cmd).filter_reported(self.log());
+        while let Some(result) = messages.next().await {
+            let message = match result {
+                Ok(message) => message,
+                // Commonly the tests not compiling, in which case the errors are among the lints.
+                Err(e) => {
+                    self.publish_partial_work(&lints.lints, &[]).await.map_err(Error::PublishLints)?;
+                    return Err(Error::Build(e));
+                }
+            };
+            let artifact = match message {
+                CargoMessageSchema::CompilerMessage(lint) => {
+                    self.add_lint(&mut lints, lint);
+                    continue;
+                }
+                CargoMessageSchema::CompilerArtifact(artifact) => artifact,
+                CargoMessageSchema::BuildScriptExecuted(build_script) => {
+                    outputs.entry(build_script.package_id).or_default().out_dir =
+                        Some(build_script.out_dir);
+                    continue;
+                }
+                CargoMessageSchema::Other => continue,
+            };
+            if !artifact.profile.test && artifact.target.kind.iter().any(|k| k == "bin") {
+                if let Some(executable) = artifact.executable {
+                    outputs.entry(artifact.package_id).or_default()
+                        .executables.push((artifact.target.name, executable));
+                }
+            } else if artifact.profile.test {
+                // Only libraries have doctests, and their unit tests are built alongside.
+                let is_library = artifact.target.kind.iter()
+                    .any(|k| matches!(&k[..], "lib" | "rlib" | "proc-macro"));
+                let package = String::from(crate::metadata::package_name(&artifact.package_id));
+                if is_library && !options.bench && !doctests.contains(&package) {
+                    doctests.push(package);
+                }
                 tests.push(artifact);
             }
         }
 
-        let mut test_results = futures::stream::iter(tests.into_iter()).map(|artifact| {
-            self.run_test(artifact)
-        }).buffer_unordered(1); // TODO: this can be >1 in most cases.
+        self.publish_partial_work(&lints.lints, &[]).await.map_err(Error::PublishLints)?;
+        if options.no_run {
+            return Ok(());
+        }
 
+        // Each test binary's output is captured separately, so running them concurrently does not
+        // mix up their results.
+        // Benchmarks running concurrently would skew each other's measurements.
+        let jobs = if options.bench {
+            1
+        } else {
+            args.value_of("test_jobs")
+                .map(|v| v.parse().expect("validated by clap"))
+                .or_else(|| {
+                    self.var_os("CARGO_BUILD_JOBS")?.to_str()?.parse().ok().filter(|&n| n > 0)
+                })
+                .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+                .unwrap_or(1)
+        };
+        // Like cargo, run the tests from the package directory. Walking up from the source files
+        // could find the manifest of another package, in nested workspaces.
+        let no_outputs = PackageOutputs::default();
+        let runner = self.runner().await;
+        let binaries = futures::stream::iter(tests).map(|artifact| {
+            let outputs = outputs.get(&artifact.package_id).unwrap_or(&no_outputs);
+            let package_dir = metadata.package_dir(&artifact.package_id);
+            let env = cargo_env(&self.cargo, &artifact, package_dir, outputs);
+            self.run_test(artifact, package_dir, env, &runner, options).boxed_local()
+        });
+        let doctests = futures::stream::iter(doctests).map(|package| {
+            self.run_doctests(package, options).boxed_local()
+        });
+        let mut test_results = binaries.chain(doctests).buffer_unordered(jobs);
+
+        // Results are published from here, as each binary finishes, rather than from within
+        // `run_test`, so that the messages are sent one at a time even if tests run concurrently.
+        let mut failure = None;
         while let Some(result) = test_results.next().await {
-            todo!()
+            let (tests, error) = result?;
+            failure = failure.or(error);
+            for test in &tests {
+                self.report_test(test);
+            }
+            self.publish_results(results, tests, args).await?;
         }
+        match failure {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
 
+    /// Publish the results as more are still to come, and add them to `results`.
+    async fn publish_results(
+        &self,
+        results: &mut Vec<crate::phab::Test>,
+        tests: Vec<crate::phab::Test>,
+        args: &clap::ArgMatches<'_>,
+    ) -> Result<(), Error> {
+        let (published, unpublished): (Vec<_>, Vec<_>) = tests.into_iter().partition(|t| {
+            // Reporting passing tests lets Harbormaster show the full picture, but some
+            // teams prefer to keep the payload small.
+            !args.is_present("only_report_failures")
+                || !matches!(t.result, crate::phab::TestResult::Pass)
+        });
+        self.publish_partial_work(&[], &published).await.map_err(Error::PublishTests)?;
+        self.count(&[], &unpublished);
+        results.extend(published);
+        results.extend(unpublished);
         Ok(())
     }
 
-    // FIXME: ideally we ask cargo to run tests instead...
-    async fn run_test(&self, artifact: ArtifactSchema) -> Result<Vec<crate::phab::Test>, Error> {
-        let mut cmd = if let Some(executable) = artifact.executable {
-            tokio::process::Command::new(executable)
+    /// How to run the test binaries built for the `--target`.
+    ///
+    /// Like cargo, the runner is used even if the target is the host. Only its environment variable
+    /// is looked at, not the `target.<triple>.runner` of the cargo configuration.
+    async fn runner(&self) -> Runner {
+        let target = match &self.target {
+            Some(target) => target,
+            None => return Runner::Host,
+        };
+        let name = format!(
+            "CARGO_TARGET_{}_RUNNER",
+            target.to_uppercase().replace(['-', '.'], "_")
+        );
+        if let Some(runner) = self.var_os(&name) {
+            let runner: Vec<_> = runner.to_string_lossy()
+                .split_whitespace()
+                .map(String::from)
+                .collect();
+            if !runner.is_empty() {
+                return Runner::Command(runner);
+            }
+        }
+        if self.host_triple().await.as_deref() == Some(&target[..]) {
+            Runner::Host
         } else {
-            eprintln!("warning: test without executable?");
-            return Ok(vec![]);
-        };
-        cmd.kill_on_drop(true);
-        let cwd = artifact.target.src_path.ancestors().filter_map(|path| {
-            let toml = path.join("Cargo.toml");
-            if toml.exists() {
-                Some(path)
-            } else {
-                None
+            Runner::Missing(name)
+        }
+    }
+
+    /// The target `rustc` builds for by default, if it can be run.
+    async fn host_triple(&self) -> Option<String> {
+        let rustc = self.var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+        let output = Command::new(rustc)
+            .arg("-vV")
+            .envs(self.env())
+            .stderr(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await
+            .ok()?;
+        let version = String::from_utf8_lossy(&output.stdout);
+        let host = version.lines().find_map(|line| line.strip_prefix("host: "))?;
+        Some(String::from(host))
+    }
+
+    /// Whether `cargo nextest` is installed.
+    async fn has_nextest(&self) -> bool {
+        Command::new(&self.cargo)
+            .arg("nextest").arg("--version")
+            .envs(self.env())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .status()
+            .await
+            .map(|status| status.success())
+            .unwrap_or(false)
+    }
+
+    /// Build and run the tests with `cargo nextest run`, which can output the results in the same
+    /// format libtest does.
+    async fn nextest_inner(
+        &self,
+        results: &mut Vec<crate::phab::Test>,
+        args: &clap::ArgMatches<'_>,
+        options: &RunOptions,
+    ) -> Result<(), Error> {
+        let mut cmd = self.cargo_command("nextest run");
+        cmd.arg("--message-format").arg("libtest-json")
+            .arg("--no-fail-fast")
+            .env("NEXTEST_EXPERIMENTAL_LIBTEST_JSON", "1")
+            .envs(options.env.iter().map(|(k, v)| (k, v)))
+            .args(&options.cargo_args);
+        if !options.harness_args.is_empty() {
+            cmd.arg("--").args(&options.harness_args);
+        }
+        let mut tests = Vec::new();
+        let mut events = self.get_stdout_json_lines(cmd).filter_reported(self.log());
+        while let Some(event) = events.next().await {
+            let event = match event {
+                Ok(event) => event,
+                // Most commonly the exit status, after all of the results have been output.
+                Err(e) => {
+                    self.publish_results(results, tests, args).await?;
+                    return Err(Error::CommandOutput(e));
+                }
+            };
+            let mut test = match event_result(event, options.output_limit) {
+                Some(test) => test,
+                None => continue,
+            };
+            // The tests are named `BINARY_ID$TEST`, where the binary ID is `PACKAGE::TARGET`.
+            if let Some((binary_id, name)) = test.name.split_once('$') {
+                test.namespace = Some(String::from(binary_id).into());
+                test.name = String::from(name).into();
             }
-        }).next();
+            self.report_test(&test);
+            tests.push(test);
+        }
+        self.publish_results(results, tests, args).await
+    }
 
-        if let Some(cwd) = cwd {
-            cmd.current_dir(cwd);
+    // FIXME: ideally we ask cargo to run tests instead...
+    async fn run_test(
+        &self,
+        artifact: ArtifactSchema,
+        cwd: Option<&Path>,
+        env: Vec<(String, std::ffi::OsString)>,
+        runner: &Runner,
+        options: &RunOptions,
+    ) -> Result<(Vec<crate::phab::Test>, Option<Error>), Error> {
+        let executable = if let Some(executable) = &artifact.executable {
+            executable
         } else {
-            eprintln!(
-                "warning: could not discover cwd for test built from {:?}",
-                artifact.target.src_path
-            );
+            self.warn("test without executable?");
+            return Ok((vec![], None));
+        };
+        let namespace = format!("{}::{}", crate::metadata::package_name(&artifact.package_id), artifact.target.name);
+        if let Runner::Missing(variable) = runner {
+            // The tests in the binary are not known without running it, so skip it as a whole.
+            let test = crate::phab::Test {
+                name: artifact.target.name.into(),
+                result: crate::phab::TestResult::Skip,
+                namespace: Some(namespace.into()),
+                duration: None,
+                details: Some(format!(
+                    "not run, it is built for --target {} and {} is not set",
+                    self.target.as_deref().unwrap_or_default(),
+                    variable
+                ).into()),
+                format: Some("text".into()),
+            };
+            return Ok((vec![test], None));
+        }
+        let make_command = || {
+            let mut cmd = match runner {
+                Runner::Command(runner) => {
+                    let mut cmd = Command::new(&runner[0]);
+                    cmd.args(&runner[1..]).arg(executable);
+                    cmd
+                }
+                Runner::Host | Runner::Missing(_) => Command::new(executable),
+            };
+            cmd.envs(env.iter().map(|(k, v)| (k, v)));
+            if let Some(cwd) = cwd {
+                cmd.current_dir(cwd);
+            }
+            cmd
+        };
+        self.run_harness(make_command, options, &artifact.target.name, namespace).await
+    }
+
+    /// Run the doctests of a library package.
+    ///
+    /// Doctests are compiled and run by rustdoc, so there is no test binary to run directly.
+    async fn run_doctests(&self, package: String, options: &RunOptions)
+    -> Result<(Vec<crate::phab::Test>, Option<Error>), Error> {
+        let cargo_args = match doctest_cargo_args(&options.cargo_args) {
+            Some(cargo_args) => cargo_args,
+            None => return Ok((vec![], None)),
+        };
+        let make_command = || {
+            let mut cmd = self.cargo_command("test");
+            cmd.arg("--doc").arg("--package").arg(&package).args(&cargo_args).arg("--");
+            cmd
+        };
+        self.run_harness(make_command, options, "doctests", format!("{}::doctests", package)).await
+    }
+}
+
+/// Run the command, unless it does not finish within `timeout`.
+async fn output_with_timeout(mut cmd: Command, timeout: Option<std::time::Duration>)
+-> Option<std::io::Result<std::process::Output>> {
+    match timeout {
+        // The child is killed when the future is dropped.
+        Some(timeout) => tokio::time::timeout(timeout, cmd.output()).await.ok(),
+        None => Some(cmd.output().await),
+    }
+}
+
+impl crate::Context {
+    /// Run a harness with `--format json`, parsing the events as it outputs them, unless it does
+    /// not finish within `timeout`.
+    async fn json_output_with_timeout(
+        &self,
+        cmd: Command,
+        output_limit: usize,
+        timeout: Option<std::time::Duration>,
+    ) -> Option<std::io::Result<(Vec<crate::phab::Test>, std::process::Output)>> {
+        let run = self.json_output(cmd, output_limit);
+        match timeout {
+            // The child is killed when the future is dropped.
+            Some(timeout) => tokio::time::timeout(timeout, run).await.ok(),
+            None => Some(run.await),
         }
+    }
 
-        // FIXME: should imitate cargo environment here.
-        let child = cmd.spawn().map_err(Error::SpawnTest)?;
-        let exit_status = child.wait_with_output().await.map_err(Error::WaitTest)?.status;
+    /// The results of the tests, and the output of the harness with the lines that are not events
+    /// as its stdout.
+    async fn json_output(&self, mut cmd: Command, output_limit: usize)
+    -> std::io::Result<(Vec<crate::phab::Test>, std::process::Output)> {
+        let mut child = cmd.spawn()?;
+        let stdout = child.stdout.take().expect("we're capturing the stdout");
+        let mut stderr = child.stderr.take().expect("we're capturing the stderr");
+        let events = async {
+            let mut tests = Vec::new();
+            let mut unparsed = Vec::new();
+            let values = self.stream_values(tokio::io::BufReader::new(stdout));
+            futures::pin_mut!(values);
+            while let Some(value) = values.next().await {
+                match value {
+                    Ok(event) => tests.extend(event_result(event, output_limit)),
+                    // What the tests print themselves, e.g. with `--nocapture`.
+                    Err(StreamValuesError::ParseLine(_, line)) => unparsed.extend(line),
+                    Err(StreamValuesError::ReadLine(e)) => return Err(e),
+                }
+            }
+            Ok((tests, unparsed))
+        };
+        // Both are read at once, so that the harness does not get stuck writing into a full pipe.
+        let mut stderr_output = Vec::new();
+        let (events, read) = futures::join!(events, stderr.read_to_end(&mut stderr_output));
+        let (tests, stdout) = events?;
+        read?;
+        let status = child.await?;
+        Ok((tests, std::process::Output { status, stdout, stderr: stderr_output }))
+    }
 
-        let result = if exit_status.success() {
-            crate::phab::TestResult::Pass
-        } else {
-            crate::phab::TestResult::Fail
+    /// Run a libtest harness and collect its results.
+    ///
+    /// In case the harness fails without any of the tests failing, or does not finish within
+    /// `--test-timeout`, a test named `name` is reported as broken in its place. The returned
+    /// error, if any, describes why the harness failed.
+    async fn run_harness(
+        &self,
+        make_command: impl Fn() -> Command,
+        options: &RunOptions,
+        name: &str,
+        namespace: String,
+    ) -> Result<(Vec<crate::phab::Test>, Option<Error>), Error> {
+        let make_command = || {
+            let mut cmd = make_command();
+            if options.bench {
+                cmd.arg("--bench");
+            }
+            cmd.args(&options.harness_args)
+                .kill_on_drop(true)
+                .stdout(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::piped())
+                .envs(options.env.iter().map(|(k, v)| (k, v)));
+            cmd
         };
+        let timed_out = || {
+            let seconds = options.timeout.map(|t| t.as_secs()).unwrap_or_default();
+            let test = crate::phab::Test {
+                name: String::from(name).into(),
+                result: crate::phab::TestResult::Broken,
+                namespace: Some(namespace.clone().into()),
+                duration: None,
+                details: Some(
+                    format!("did not finish within {} seconds and was killed", seconds).into()
+                ),
+                format: Some("text".into()),
+            };
+            (vec![test], Some(Error::TestTimeout(seconds)))
+        };
+        let json_command = || {
+            let mut cmd = make_command();
+            cmd.arg("--format").arg("json").arg("--report-time").arg("-Z").arg("unstable-options");
+            cmd
+        };
+        let refused_unstable = |output: &std::process::Output, tests: &[crate::phab::Test]| {
+            !output.status.success() && tests.is_empty()
+                && String::from_utf8_lossy(&output.stderr).contains("only accepted on the nightly")
+        };
+        let pretty_only = &options.pretty_only;
+        let started = std::time::Instant::now();
+        let mut json = None;
+        if !pretty_only.load(std::sync::atomic::Ordering::Relaxed) {
+            let run = self.json_output_with_timeout(
+                json_command(),
+                options.output_limit,
+                options.timeout,
+            );
+            json = match run.await {
+                Some(result) => Some(result.map_err(Error::SpawnTest)?),
+                None => return Ok(timed_out()),
+            };
+        }
+        let (mut tests, output) = match json {
+            Some((tests, output)) if !refused_unstable(&output, &tests) => (tests, output),
+            _ => {
+                // Stable toolchains refuse the JSON output. `RUSTC_BOOTSTRAP` would unlock it, but
+                // also every other nightly feature for the tests, so the results are read from the
+                // pretty output instead, without the durations. The refused attempt counts
+                // towards the timeout.
+                pretty_only.store(true, std::sync::atomic::Ordering::Relaxed);
+                let timeout = options.timeout.map(|t| t.saturating_sub(started.elapsed()));
+                let output = match output_with_timeout(make_command(), timeout).await {
+                    Some(output) => output.map_err(Error::SpawnTest)?,
+                    None => return Ok(timed_out()),
+                };
+                (parse_pretty_output(&output.stdout, options.output_limit), output)
+            }
+        };
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        eprint!("{}", stderr);
+        // With `--nocapture` the panics of the failed tests are only on stderr.
+        let mut panics = stderr_panics(&stderr);
+        for test in &mut tests {
+            let has_output = test.details.as_deref().is_some_and(|d| !d.is_empty());
+            if !matches!(test.result, crate::phab::TestResult::Fail) || has_output {
+                continue;
+            }
+            if let Some(panic) = panics.remove(&*test.name) {
+                test.details = Some(truncate_output(panic.trim(), options.output_limit).into());
+                test.format = Some("text".into());
+            }
+        }
+        if options.bench {
+            // In benchmark mode, libtest reports all of the regular tests as ignored.
+            tests.retain(|t| !matches!(t.result, crate::phab::TestResult::Skip));
+        }
 
-        Ok(vec![crate::phab::Test {
-            name: "todo".into(),
-            result,
-            namespace: None,
-            duration: None,
-            // TODO: add output of the test suite
-            details: None,
-            format: None,
-        }])
+        if !output.status.success() && !tests.iter().any(|t| {
+            matches!(t.result, crate::phab::TestResult::Fail)
+        }) {
+            // The test binary failed without any of the tests failing (e.g. it crashed or does
+            // not use the libtest harness). Report the binary as a whole instead.
+            let output = format!(
+                "{}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            );
+            tests.push(crate::phab::Test {
+                name: String::from(name).into(),
+                result: crate::phab::TestResult::Broken,
+                namespace: None,
+                duration: None,
+                details: Some(truncate_output(output.trim(), options.output_limit).into()),
+                format: Some("text".into()),
+            });
+        }
+        for test in &mut tests {
+            test.namespace = Some(namespace.clone().into());
+        }
+        let error = if output.status.success() {
+            None
+        } else {
+            Some(Error::TestStatus(output.status))
+        };
+        Ok((tests, error))
     }
 }